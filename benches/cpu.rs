@@ -0,0 +1,320 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use stack_based_virtual_machine::parser::assembler::Assembler;
+use stack_based_virtual_machine::parser::lexer::Lexer;
+use stack_based_virtual_machine::vm::cpu::{Value, CPU};
+use stack_based_virtual_machine::vm::host::Host;
+use stack_based_virtual_machine::vm::instruction::Opcode;
+use stack_based_virtual_machine::vm::predecode::{predecode, Instr};
+use std::collections::HashMap;
+
+// Sums the squares of 1 to 100000 using nothing but the stack.
+const ARITHMETIC_LOOP: &str = "
+    PUSH    0
+    PUSH    0
+
+loop:
+    PUSH    1
+    ADD
+    DUP
+    DUP
+    MUL
+    ROT
+    ADD
+    SWAP
+    DUP
+    PUSHW   100000
+    CMP
+    POP
+    JNE     loop
+
+    POP
+    HALT
+";
+
+// Counts the multiples of 3 and 5 below 100000 the way fizz_buzz finds them, without the printing.
+const FIZZ_BUZZ_LOOP: &str = "
+    .global hits
+    PUSH    0
+    GSTORE  hits
+    PUSH    0
+
+loop:
+    PUSH    1
+    ADD
+    DUP
+    PUSH    3
+    MOD
+    JNE     not_3
+    GLOAD   hits
+    PUSH    1
+    ADD
+    GSTORE  hits
+not_3:
+    POP
+    DUP
+    PUSH    5
+    MOD
+    JNE     not_5
+    GLOAD   hits
+    PUSH    1
+    ADD
+    GSTORE  hits
+not_5:
+    POP
+    DUP
+    PUSHW   100000
+    CMP
+    POP
+    JL      loop
+
+    GLOAD   hits
+    HALT
+";
+
+// Works out the 22nd Fibonacci number the slow way, making about 57000 calls.
+const RECURSION: &str = "
+    PUSH    22
+    CALL    fib 0 1
+    HALT
+
+fib:
+    LOAD    0
+    PUSH    1
+    CMP
+    POP
+    JLE     base
+
+    LOAD    0
+    PUSH    1
+    SUB
+    CALL    fib 0 1
+    LOAD    0
+    PUSH    2
+    SUB
+    CALL    fib 0 1
+    ADD
+    RETURN  1
+
+base:
+    LOAD    0
+    RETURN  1
+";
+
+const FIZZ_BUZZ: &str = include_str!("../nar_files/fizz_buzz.nar");
+
+/// Throws away everything the program prints, so the benchmark measures the CPU rather than the terminal.
+struct Sink;
+
+impl Host for Sink {
+    fn read_line(&mut self) -> Option<String> {
+        None
+    }
+
+    fn write(&mut self, _text: &str) {}
+}
+
+fn assemble(source: &str) -> Vec<u32> {
+    let mut lexer = Lexer::new(source);
+    lexer.lex().unwrap();
+
+    let mut assembler = Assembler::new(lexer.tokens, "");
+    assembler.assemble().unwrap();
+    assembler.output
+}
+
+fn instructions_executed(program: &[u32]) -> u64 {
+    let mut cpu = CPU::with_host(program.to_vec(), Sink);
+    cpu.run().unwrap();
+    cpu.instructions_executed()
+}
+
+fn cpu(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu");
+
+    let programs = [
+        ("arithmetic_loop", ARITHMETIC_LOOP),
+        ("fizz_buzz_loop", FIZZ_BUZZ_LOOP),
+        ("recursion", RECURSION),
+        ("fizz_buzz", FIZZ_BUZZ),
+    ];
+
+    for (name, source) in programs.iter() {
+        let program = assemble(source);
+
+        // Creating the CPU copies and predecodes the program, which isn't part of running it.
+        group.throughput(Throughput::Elements(instructions_executed(&program)));
+        group.bench_function(*name, |b| b.iter_batched(
+            || CPU::with_host(program.clone(), Sink),
+            |mut cpu| cpu.run().unwrap(),
+            BatchSize::SmallInput,
+        ));
+    }
+
+    group.finish();
+}
+
+/// The dispatch loop as it was before programs were predecoded: every step decodes its word again,
+/// and a jump is signalled by returning an allocated `String`. It only knows the opcodes
+/// `FIZZ_BUZZ_LOOP` uses, which is enough to compare against the predecoded CPU.
+fn run_undecoded(program: &[u32]) -> Value {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut globals: HashMap<i16, Value> = HashMap::new();
+    let (mut zero_flag, mut sign_flag) = (false, false);
+    let mut address = 0;
+
+    loop {
+        let (opcode, operand1, operand2) = Opcode::decode(program[address]);
+        let jumped: Option<String> = match opcode {
+            Opcode::HALT => return stack.pop().unwrap_or(0),
+            Opcode::POP => {
+                stack.pop().unwrap();
+                None
+            },
+            Opcode::PUSH => {
+                stack.push(operand1 as Value);
+                None
+            },
+            Opcode::PUSHW => {
+                stack.push(((program[address + 1] as u64) << 32 | program[address + 2] as u64) as Value);
+                address += 2;
+                None
+            },
+            Opcode::DUP => {
+                stack.push(*stack.last().unwrap());
+                None
+            },
+            Opcode::ADD | Opcode::MOD | Opcode::CMP => {
+                let n1 = stack.pop().unwrap();
+                let n2 = stack.pop().unwrap();
+                let result = match opcode {
+                    Opcode::ADD => n2.wrapping_add(n1),
+                    Opcode::MOD => n2.wrapping_rem(n1),
+                    _ => n2.wrapping_sub(n1),
+                };
+                stack.push(result);
+
+                zero_flag = result == 0;
+                if result != 0 {
+                    sign_flag = result > 0;
+                }
+                None
+            },
+            Opcode::JNE | Opcode::JL => {
+                let taken = match opcode {
+                    Opcode::JNE => !zero_flag,
+                    _ => !sign_flag,
+                };
+                if taken {
+                    address = opcode.target(address, operand1, operand2) as usize;
+                    Some("jumped".into())
+                } else {
+                    None
+                }
+            },
+            Opcode::GLOAD => {
+                stack.push(globals[&operand1]);
+                None
+            },
+            Opcode::GSTORE => {
+                globals.insert(operand1, stack.pop().unwrap());
+                None
+            },
+            _ => unimplemented!("{:?}", opcode),
+        };
+
+        if jumped.is_none() {
+            address += 1;
+        }
+    }
+}
+
+/// `run_undecoded` over the `Instr` stream the CPU runs, so the two differ only in decoding.
+fn run_predecoded(instrs: &[Instr]) -> Value {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut globals: HashMap<i16, Value> = HashMap::new();
+    let (mut zero_flag, mut sign_flag) = (false, false);
+    let mut address = 0;
+
+    loop {
+        let instr = instrs[address];
+        let jumped = match instr {
+            Instr::Halt => return stack.pop().unwrap_or(0),
+            Instr::Pop => {
+                stack.pop().unwrap();
+                false
+            },
+            Instr::Push(value) => {
+                stack.push(value);
+                false
+            },
+            Instr::PushWide(value) => {
+                stack.push(value);
+                address += 2;
+                false
+            },
+            Instr::Dup => {
+                stack.push(*stack.last().unwrap());
+                false
+            },
+            Instr::Add | Instr::Mod | Instr::Cmp => {
+                let n1 = stack.pop().unwrap();
+                let n2 = stack.pop().unwrap();
+                let result = match instr {
+                    Instr::Add => n2.wrapping_add(n1),
+                    Instr::Mod => n2.wrapping_rem(n1),
+                    _ => n2.wrapping_sub(n1),
+                };
+                stack.push(result);
+
+                zero_flag = result == 0;
+                if result != 0 {
+                    sign_flag = result > 0;
+                }
+                false
+            },
+            Instr::Jne(target) | Instr::Jl(target) => {
+                let taken = match instr {
+                    Instr::Jne(_) => !zero_flag,
+                    _ => !sign_flag,
+                };
+                if taken {
+                    address = target as usize;
+                }
+                taken
+            },
+            Instr::GLoad(n) => {
+                stack.push(globals[&n]);
+                false
+            },
+            Instr::GStore(n) => {
+                globals.insert(n, stack.pop().unwrap());
+                false
+            },
+            _ => unimplemented!("{:?}", instr),
+        };
+
+        if !jumped {
+            address += 1;
+        }
+    }
+}
+
+// Runs the same loop over raw words and over predecoded instructions, to show what predecoding
+// saves on every step. The CPU itself does more per step, checking limits and depths, so it's in
+// the `cpu` group rather than here.
+fn dispatch(c: &mut Criterion) {
+    let program = assemble(FIZZ_BUZZ_LOOP);
+    let instrs = predecode(&program);
+    let expected = CPU::with_host(program.clone(), Sink).run().unwrap();
+    assert_eq!(expected, run_undecoded(&program));
+    assert_eq!(expected, run_predecoded(&instrs));
+
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(instructions_executed(&program)));
+    group.bench_function("undecoded", |b| b.iter(|| run_undecoded(&program)));
+    group.bench_function("predecoded", |b| b.iter(|| run_predecoded(&instrs)));
+    group.finish();
+}
+
+criterion_group!(benches, cpu, dispatch);
+criterion_main!(benches);
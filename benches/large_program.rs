@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use stack_based_virtual_machine::parser::assembler::Assembler;
+use stack_based_virtual_machine::parser::lexer::Lexer;
+use stack_based_virtual_machine::parser::reader::Reader;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+
+// Big enough that anything quadratic in the program's length takes minutes rather than milliseconds.
+const INSTRUCTIONS: usize = 1_000_000;
+
+/// Writes a program of `instructions` instructions, alternating `PUSH n` and `POP`.
+fn generate(instructions: usize) -> String {
+    let mut source = String::new();
+    for n in 0..instructions / 2 {
+        let _ = write!(source, "    PUSH    {}\n    POP\n", n % 1000);
+    }
+    source
+}
+
+fn large_program(c: &mut Criterion) {
+    let mut lexer = Lexer::new(generate(INSTRUCTIONS));
+    lexer.lex().unwrap();
+    let tokens = lexer.tokens;
+
+    let file_path = env::temp_dir().join("large_program.bin").to_string_lossy().into_owned();
+    let mut assembler = Assembler::new(tokens.clone(), file_path.as_str());
+    assembler.assemble().unwrap();
+    assembler.write().unwrap();
+
+    let mut group = c.benchmark_group("large_program");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(INSTRUCTIONS as u64));
+
+    group.bench_function("assemble", |b| b.iter_batched(
+        || tokens.clone(),
+        |tokens| Assembler::new(tokens, "").assemble().unwrap(),
+        BatchSize::LargeInput,
+    ));
+    group.bench_function("read", |b| b.iter(|| Reader::read(file_path.as_str()).unwrap()));
+
+    group.finish();
+    let _ = fs::remove_file(&file_path);
+}
+
+criterion_group!(benches, large_program);
+criterion_main!(benches);
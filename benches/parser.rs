@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use stack_based_virtual_machine::parser::assembler::Assembler;
+use stack_based_virtual_machine::parser::lexer::Lexer;
+use stack_based_virtual_machine::parser::tokens::Token;
+
+// How many functions `generate` writes. Each one is 18 lines of source.
+const FUNCTIONS: usize = 1000;
+
+/// Writes a program that calls `functions` small functions in turn, using every kind of token
+/// the lexer knows about: labels, numbers, floats, characters, directives and comments.
+fn generate(functions: usize) -> String {
+    let mut source = String::from(".global total\n    PUSH    0\n    GSTORE  total\n\n");
+
+    for n in 0..functions {
+        source.push_str(&format!("    PUSH    {}\n    CALL    f{} 0 1\n", n, n));
+    }
+    source.push_str("    GLOAD   total\n    HALT\n");
+
+    for n in 0..functions {
+        source.push_str(&format!("
+// Adds its argument to the total, and prints a character if it's odd.
+f{n}:
+    LOAD    0
+    PUSH    2
+    MOD
+    JE      f{n}_even
+    PUSH    'x'
+    STDOUT  3
+    POP
+f{n}_even:
+    POP
+    GLOAD   total
+    LOAD    0
+    ADD
+    GSTORE  total
+    PUSH    {f:?}   /* a float nobody uses */
+    POP
+    RETURN
+", n = n, f = n as f64 / 4.0));
+    }
+
+    source
+}
+
+fn lex(source: &str) -> Vec<Token> {
+    let mut lexer = Lexer::new(source);
+    lexer.lex().unwrap();
+    lexer.tokens
+}
+
+fn parser(c: &mut Criterion) {
+    let source = generate(FUNCTIONS);
+    let tokens = lex(&source);
+
+    let mut group = c.benchmark_group("parser");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(source.len() as u64));
+
+    group.bench_function("lex", |b| b.iter(|| lex(&source)));
+    group.bench_function("assemble", |b| b.iter_batched(
+        || tokens.clone(),
+        |tokens| Assembler::new(tokens, "").assemble().unwrap(),
+        BatchSize::LargeInput,
+    ));
+
+    group.finish();
+}
+
+criterion_group!(benches, parser);
+criterion_main!(benches);
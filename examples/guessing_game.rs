@@ -1,5 +1,4 @@
 extern crate stack_based_virtual_machine;
-use stack_based_virtual_machine::vm::instruction::*;
 use stack_based_virtual_machine::vm::cpu::*;
 use stack_based_virtual_machine::parser::lexer::*;
 use stack_based_virtual_machine::parser::assembler::*;
@@ -9,15 +8,23 @@ use std::fs::read_to_string;
 
 pub fn main() {
     let file = read_to_string("nar_files/guessing_game.nar").unwrap() as String;
-    let mut lexer = Lexer::new(file);
-    lexer.lex();
+    let mut lexer = Lexer::with_file(file.clone(), "nar_files/guessing_game.nar");
+    if let Err(diagnostics) = lexer.lex() {
+        diagnostics.iter().for_each(|d| eprint!("{}", d.render(&file)));
+        return;
+    }
 
-    let mut assembler = Assembler::new(lexer.tokens, "binaries/guessing_game.bin");
-    assembler.assemble();
-    assembler.write();
+    let mut assembler = Assembler::with_source_file(lexer.tokens, "binaries/guessing_game.bin", "nar_files/guessing_game.nar");
+    if let Err(diagnostics) = assembler.assemble() {
+        diagnostics.iter().for_each(|d| eprint!("{}", d.render(&file)));
+        return;
+    }
+    assembler.write().unwrap();
 
-    let program: Vec<u32> = Reader::read("binaries/guessing_game.bin");
+    let program: Vec<u32> = Reader::read("binaries/guessing_game.bin").unwrap();
 
     let mut cpu = CPU::new(program);
-    cpu.run();
+    if let Err(e) = cpu.run() {
+        println!("{}", e);
+    }
 }
\ No newline at end of file
@@ -1,5 +1,4 @@
 extern crate stack_based_virtual_machine;
-use stack_based_virtual_machine::vm::instruction::*;
 use stack_based_virtual_machine::vm::cpu::*;
 use stack_based_virtual_machine::parser::lexer::*;
 use stack_based_virtual_machine::parser::assembler::*;
@@ -9,14 +8,22 @@ use std::fs::read_to_string;
 
 pub fn main() {
     let file = read_to_string("nar_files/minus.nar").unwrap() as String;
-    let mut lexer = Lexer::new(file);
-    lexer.lex();
+    let mut lexer = Lexer::with_file(file.clone(), "nar_files/minus.nar");
+    if let Err(diagnostics) = lexer.lex() {
+        diagnostics.iter().for_each(|d| eprint!("{}", d.render(&file)));
+        return;
+    }
 
-    let mut assembler = Assembler::new(lexer.tokens, "binaries/minus.bin");
-    assembler.assemble();
-    assembler.write();
+    let mut assembler = Assembler::with_source_file(lexer.tokens, "binaries/minus.bin", "nar_files/minus.nar");
+    if let Err(diagnostics) = assembler.assemble() {
+        diagnostics.iter().for_each(|d| eprint!("{}", d.render(&file)));
+        return;
+    }
+    assembler.write().unwrap();
 
-    let program = Reader::read("binaries/minus.bin");
+    let program = Reader::read("binaries/minus.bin").unwrap();
     let mut cpu = CPU::new(program);
-    cpu.run();
+    if let Err(e) = cpu.run() {
+        println!("{}", e);
+    }
 }
\ No newline at end of file
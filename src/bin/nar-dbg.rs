@@ -0,0 +1,359 @@
+extern crate stack_based_virtual_machine;
+use stack_based_virtual_machine::vm::cpu::*;
+use stack_based_virtual_machine::vm::debugger::*;
+use stack_based_virtual_machine::vm::error::*;
+use stack_based_virtual_machine::vm::host::*;
+use stack_based_virtual_machine::parser::lexer::*;
+use stack_based_virtual_machine::parser::assembler::*;
+use stack_based_virtual_machine::parser::disassembler::*;
+use stack_based_virtual_machine::parser::reader::*;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{read_to_string, File};
+use std::io::{stdin, stdout, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage: nar-dbg <file.nar|file.bin> [--input <file>]";
+
+const HELP: &str = "\
+commands:
+    break <label|address> [if <==|!=|<|> value]    set a breakpoint
+    delete <label|address>                         remove breakpoints
+    watch <variable>                               stop when a variable in the current frame changes
+    unwatch <variable>                             remove a watchpoint
+    step | s                                       execute one instruction
+    next | n                                       execute one instruction, stepping over CALL
+    finish | f                                     run until the current function returns
+    continue | c                                   run until a breakpoint, watchpoint or HALT
+    stack                                          print the operand stack
+    locals                                         print the current frame's variables
+    globals                                        print the global variables
+    backtrace | bt                                 print the call stack
+    flags                                          print the program counter and flags
+    memory [address] [n]                           print n words of memory from address
+    disas [n]                                      disassemble n instructions around the program counter
+    labels                                         list the program's labels
+    quit | q                                       exit the debugger
+an empty line repeats the previous command";
+
+fn load(file_path: &str) -> (Vec<u32>, HashMap<String, usize>) {
+    if file_path.ends_with(".nar") {
+        let source = match read_to_string(file_path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("couldn't read {}: {}", file_path, e);
+                process::exit(1);
+            }
+        };
+
+        let mut lexer = Lexer::with_file(source.clone(), file_path);
+        let mut diagnostics = match lexer.lex() {
+            Ok(()) => Vec::new(),
+            Err(d) => d,
+        };
+
+        let mut assembler = Assembler::with_source_file(lexer.tokens, "", file_path);
+        if diagnostics.is_empty() {
+            if let Err(d) = assembler.assemble() {
+                diagnostics = d;
+            }
+        }
+
+        if !diagnostics.is_empty() {
+            diagnostics.iter().for_each(|d| eprint!("{}", d.render(&source)));
+            process::exit(1);
+        }
+        (assembler.output, assembler.labels)
+    } else {
+        let symbols = Reader::read_symbols(Path::new(file_path).with_extension("sym").to_string_lossy());
+        match Reader::read(file_path) {
+            Ok(program) => (program, symbols.unwrap_or_default()),
+            Err(e) => {
+                eprintln!("couldn't load {}: {}", file_path, e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Feeds the program's `STDIN` from the file given with `--input`, so it can't read the debugger's
+/// own commands. Without one, `STDIN` faults with no more input. `STDOUT` goes to stdout as usual.
+struct DebuggeeHost {
+    input: Option<BufReader<File>>,
+}
+
+impl Host for DebuggeeHost {
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match self.input.as_mut()?.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(&['\r', '\n'][..]).into()),
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        StdHost.write(text);
+    }
+}
+
+struct Session {
+    debugger: Debugger<DebuggeeHost>,
+    labels: HashMap<String, usize>,
+    finished: bool,
+}
+
+impl Session {
+    fn resolve(&self, location: &str) -> Option<usize> {
+        match self.labels.get(location) {
+            Some(address) => Some(*address),
+            None => location.parse::<usize>().ok(),
+        }
+    }
+
+    fn execute(&mut self, args: &[&str]) -> bool {
+        match args {
+            ["quit"] | ["q"] => return false,
+            ["help"] | ["h"] => println!("{}", HELP),
+
+            ["break", location, rest @ ..] | ["b", location, rest @ ..] => {
+                let address = match self.resolve(location) {
+                    Some(a) => a,
+                    None => {
+                        println!("unknown label or address: {}", location);
+                        return true;
+                    }
+                };
+
+                match rest {
+                    [] => self.debugger.add_breakpoint(address),
+                    ["if", op, value] => {
+                        let value = match value.parse::<Value>() {
+                            Ok(v) => v,
+                            Err(_) => {
+                                println!("invalid value: {}", value);
+                                return true;
+                            }
+                        };
+                        let condition = match *op {
+                            "==" => Condition::Equal(value),
+                            "!=" => Condition::NotEqual(value),
+                            "<" => Condition::Less(value),
+                            ">" => Condition::Greater(value),
+                            _ => {
+                                println!("invalid comparison: {}", op);
+                                return true;
+                            }
+                        };
+                        self.debugger.add_conditional_breakpoint(address, condition);
+                    },
+                    _ => {
+                        println!("usage: break <label|address> [if <==|!=|<|> value]");
+                        return true;
+                    }
+                }
+                println!("breakpoint set at {}", address);
+            },
+            ["delete", location] | ["d", location] => {
+                match self.resolve(location) {
+                    Some(address) if self.debugger.remove_breakpoint(address) => println!("breakpoint at {} removed", address),
+                    _ => println!("no breakpoint at {}", location),
+                }
+            },
+
+            ["watch", variable] | ["unwatch", variable] => {
+                let variable = match variable.parse::<i16>() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        println!("invalid variable: {}", variable);
+                        return true;
+                    }
+                };
+                let depth = self.debugger.cpu.call_stack.len() - 1;
+
+                if args[0] == "watch" {
+                    self.debugger.add_watchpoint(depth, variable);
+                    println!("watching variable {} in frame {}", variable, depth);
+                } else if !self.debugger.remove_watchpoint(depth, variable) {
+                    println!("variable {} isn't being watched in frame {}", variable, depth);
+                }
+            },
+
+            ["step"] | ["s"] => self.resume(|d| d.step()),
+            ["next"] | ["n"] => self.resume(|d| d.step_over()),
+            ["finish"] | ["f"] => self.resume(|d| d.step_out()),
+            ["continue"] | ["c"] => self.resume(|d| d.run_until_breakpoint()),
+
+            ["stack"] => println!("{:?}", self.debugger.cpu.stack),
+            ["locals"] => {
+                let depth = self.debugger.cpu.call_stack.len() - 1;
+                for (key, value) in self.debugger.cpu.locals(depth).unwrap_or_default().iter().enumerate() {
+                    println!("{} = {}", key, value);
+                }
+            },
+            ["globals"] => {
+                let mut globals: Vec<(&i16, &Value)> = self.debugger.cpu.globals().iter().collect();
+                globals.sort();
+                for (key, value) in globals {
+                    println!("{} = {}", key, value);
+                }
+            },
+            ["backtrace"] | ["bt"] => {
+                println!("#0 {}", self.debugger.cpu.current_address());
+                for (depth, frame) in self.debugger.cpu.call_stack.iter().skip(1).rev().enumerate() {
+                    println!("#{} {}", depth + 1, frame.return_address);
+                }
+            },
+            ["memory", rest @ ..] if rest.len() <= 2 => {
+                let parsed: Result<Vec<usize>, _> = rest.iter().map(|n| n.parse::<usize>()).collect();
+                let (start, count) = match parsed.as_deref() {
+                    Ok([]) => (0, 16),
+                    Ok([start]) => (*start, 16),
+                    Ok([start, count]) => (*start, *count),
+                    _ => {
+                        println!("usage: memory [address] [n]");
+                        return true;
+                    }
+                };
+
+                let memory = self.debugger.cpu.memory();
+                println!("{} words", memory.len());
+                for (address, value) in memory.iter().enumerate().skip(start).take(count) {
+                    println!("{:>5} {}", address, value);
+                }
+            },
+            ["flags"] => {
+                let cpu = &self.debugger.cpu;
+                println!("pc = {}, zero = {}, sign = {}, overflow = {}",
+                    cpu.current_address(), cpu.zero_flag(), cpu.sign_flag(), cpu.overflow_flag());
+            },
+            ["disas"] => self.disassemble(5),
+            ["disas", n] => match n.parse::<usize>() {
+                Ok(n) => self.disassemble(n),
+                Err(_) => println!("invalid count: {}", n),
+            },
+            ["labels"] => {
+                let mut labels: Vec<(&String, &usize)> = self.labels.iter().collect();
+                labels.sort_by_key(|(name, address)| (**address, name.as_str()));
+                for (name, address) in labels {
+                    println!("{:>5} {}", address, name);
+                }
+            },
+
+            [] => (),
+            _ => println!("unknown command, type `help` for a list of commands"),
+        }
+
+        true
+    }
+
+    fn resume<F: FnOnce(&mut Debugger<DebuggeeHost>) -> Result<StopReason, Fault>>(&mut self, action: F) {
+        if self.finished {
+            println!("the program has finished");
+            return;
+        }
+
+        match action(&mut self.debugger) {
+            Ok(StopReason::Step) => (),
+            Ok(StopReason::Breakpoint(address)) => println!("breakpoint hit at {}", address),
+            Ok(StopReason::Watchpoint { watchpoint, old, new }) => {
+                println!("variable {} in frame {} changed: {:?} -> {:?}", watchpoint.variable, watchpoint.depth, old, new);
+            },
+            Ok(StopReason::Halted(value)) => {
+                println!("program halted with {}", value);
+                self.finished = true;
+                return;
+            },
+            Err(fault) => {
+                println!("fault: {}", fault);
+                self.finished = true;
+                return;
+            }
+        }
+
+        self.disassemble(0);
+    }
+
+    fn disassemble(&self, context: usize) {
+        let program = self.debugger.cpu.program();
+        let disassembler = Disassembler::with_symbols(program, &self.labels);
+        let pc = self.debugger.cpu.current_address();
+        let start = pc.saturating_sub(context);
+        let end = (pc + context + 1).min(program.len());
+
+        for address in disassembler.addresses().iter().copied().filter(|a| (start..end).contains(a)) {
+            for label in disassembler.labels_at(address) {
+                println!("{}:", label);
+            }
+
+            let marker = if address == pc { "=>" } else { "  " };
+            println!("{} {:>5}    {}", marker, address, disassembler.render_instruction(address));
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut file_path = None;
+    let mut input_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => match args.next() {
+                Some(p) => input_path = Some(p),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            _ if file_path.is_none() => file_path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(1);
+            }
+        }
+    }
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    let input = match input_path.map(File::open) {
+        Some(Ok(file)) => Some(BufReader::new(file)),
+        Some(Err(e)) => {
+            eprintln!("couldn't open {}: {}", input_path.unwrap(), e);
+            process::exit(1);
+        },
+        None => None,
+    };
+
+    let (program, labels) = load(file_path);
+    let cpu = CPU::with_host(program, DebuggeeHost { input });
+    let mut session = Session { debugger: Debugger::new(cpu), labels, finished: false };
+    session.disassemble(0);
+
+    let mut previous = String::new();
+    loop {
+        print!("(nar-dbg) ");
+        let _ = stdout().flush();
+
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        if line.trim().is_empty() {
+            line = previous.clone();
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if !session.execute(&args) {
+            break;
+        }
+        previous = line;
+    }
+}
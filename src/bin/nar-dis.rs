@@ -0,0 +1,36 @@
+extern crate stack_based_virtual_machine;
+use stack_based_virtual_machine::parser::disassembler::*;
+use stack_based_virtual_machine::parser::reader::*;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let addresses = args.iter().any(|a| a == "--addresses");
+    let file_path = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(p) => p,
+        None => {
+            eprintln!("usage: nar-dis <file.bin> [--addresses]");
+            process::exit(1);
+        }
+    };
+
+    let program = match Reader::read(file_path.as_str()) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("couldn't load {}: {}", file_path, e);
+            process::exit(1);
+        }
+    };
+    let symbols_path = Path::new(file_path).with_extension("sym");
+    let symbols = Reader::read_symbols(symbols_path.to_string_lossy()).unwrap_or_default();
+
+    let disassembler = Disassembler::with_symbols(&program, &symbols);
+    if addresses {
+        print!("{}", disassembler.listing());
+    } else {
+        print!("{}", disassembler.disassemble());
+    }
+}
@@ -1,20 +1,10 @@
 pub mod vm;
 pub mod parser;
 
-use vm::cpu::*;
-use vm::frame::*;
-use vm::instruction::*;
-
-use parser::assembler::*;
-use parser::reader::*;
-use parser::lexer::*;
-use parser::tokens::*;
-
 
 #[cfg(test)]
 mod test_instruction {
-    //use crate::instruction::*;
-    use super::*;
+    use crate::vm::instruction::*;
 
     #[test]
     fn operation_decode_encode() {
@@ -22,21 +12,55 @@ mod test_instruction {
         let operand1: i16 = 1;
         let operand2: i8 = 2;
 
-        let instruction: u32 = Opcode::encode(opcode.clone(), 1, 2);
+        let instruction: u32 = Opcode::encode(opcode, 1, 2);
         assert_eq!((opcode, operand1, operand2), Opcode::decode(instruction));
+
+        for (operand1, operand2) in [(-1, -1), (300, 5), (i16::MIN, i8::MIN), (i16::MAX, i8::MAX)].iter() {
+            let instruction = Opcode::encode(Opcode::PUSH, *operand1, *operand2);
+            assert_eq!((Opcode::PUSH, *operand1, *operand2), Opcode::decode(instruction));
+        }
     }
 
     #[test]
     fn instruction_byte() {
-        let instruction = 0xb32 as u32;
+        let instruction = 0xb32_u32;
         println!("{}", instruction);
         assert_eq!(instruction, Opcode::byte_array_to_instruction(Opcode::instruction_to_byte_array(instruction)));
     }
+
+    #[test]
+    fn predecode() {
+        use crate::vm::predecode::*;
+
+        let program = vec![
+            Opcode::encode(Opcode::JMP, 3, 1),
+            Opcode::encode(Opcode::PUSHW, 0, 0),
+            1,
+            2,
+            Opcode::encode(Opcode::JNE, 4, 2),
+            Opcode::encode(Opcode::CALL, 1, Opcode::pack_call(0, 2)),
+            Opcode::encode(Opcode::PUSHW, 0, 0),
+            0,
+        ];
+
+        assert_eq!(vec![
+            Instr::Jmp(3),
+            Instr::PushWide(1 << 32 | 2),
+            Instr::Halt,
+            Instr::Halt,
+            Instr::Jne(0),
+            Instr::Call { target: 1, arguments: 2 },
+            Instr::PushTruncated,
+            Instr::Halt,
+        ], predecode(&program));
+    }
 }
 
 #[cfg(test)]
 mod test_cpu {
-    use super::*;
+    use crate::vm::cpu::*;
+    use crate::vm::error::*;
+    use crate::vm::instruction::*;
 
     #[test]
     fn arithmetic() {
@@ -80,41 +104,410 @@ mod test_cpu {
         let program = vec![
             Opcode::encode(Opcode::PUSH, 10, 0),
             Opcode::encode(Opcode::PUSH, 5, 0),
-            Opcode::encode(Opcode::CALL, 3, 1),
+            Opcode::encode(Opcode::CALL, 3, Opcode::pack_call(1, 2)),
             Opcode::encode(Opcode::STDOUT, 0, 0),
             Opcode::encode(Opcode::HALT, 1, 0),
 
 
-            //function that returns the largest number
-            Opcode::encode(Opcode::STORE, 0, 0),
-            Opcode::encode(Opcode::STORE, 1, 0),
-            Opcode::encode(Opcode::LOAD, 0, 0),
+            //function that returns the largest of its two arguments
             Opcode::encode(Opcode::LOAD, 1, 0),
+            Opcode::encode(Opcode::LOAD, 0, 0),
             Opcode::encode(Opcode::CMP, 0, 0),
+            Opcode::encode(Opcode::POP, 0, 0),
             Opcode::encode(Opcode::JL, 3, 1),
+            Opcode::encode(Opcode::LOAD, 1, 0),
+            Opcode::encode(Opcode::RETURN, 1, 0),
+
             Opcode::encode(Opcode::LOAD, 0, 0),
             Opcode::encode(Opcode::RETURN, 1, 0),
+        ];
+
+        let mut cpu = CPU::new(program);
+        assert_eq!(10, cpu.run().unwrap());
+        assert!(cpu.stack.is_empty());
+    }
+
+    #[test]
+    fn locals() {
+        let program = vec![
+            Opcode::encode(Opcode::ENTER, 1, 0),
+            Opcode::encode(Opcode::PUSH, 7, 0),
+            Opcode::encode(Opcode::STORE, 0, 0),
+            Opcode::encode(Opcode::PUSH, 3, 0),
+            Opcode::encode(Opcode::CALL, 7, Opcode::pack_call(0, 1)),
+            Opcode::encode(Opcode::LOAD, 0, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
 
+            Opcode::encode(Opcode::ENTER, 2, 0),
+            Opcode::encode(Opcode::LOAD, 1, 0),
+            Opcode::encode(Opcode::LOAD, 0, 0),
+            Opcode::encode(Opcode::ADD, 0, 0),
+            Opcode::encode(Opcode::STORE, 1, 0),
             Opcode::encode(Opcode::LOAD, 1, 0),
             Opcode::encode(Opcode::RETURN, 1, 0),
         ];
 
+        // Slots start at 0, and the caller's slots are left alone by the callee.
         let mut cpu = CPU::new(program);
-        assert_eq!(10, cpu.run().unwrap());
+        assert_eq!(7, cpu.run().unwrap());
+        assert_eq!(vec![3], cpu.stack);
+        assert_eq!(Some(&[7][..]), cpu.locals(0));
+        assert_eq!(None, cpu.locals(1));
+
+        // Frames without an ENTER grow as variables are stored, and unset ones read as 0.
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::STORE, 0, 0),
+            Opcode::encode(Opcode::LOAD, 2, 0),
+            Opcode::encode(Opcode::LOAD, 0, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+        ];
+        let mut cpu = CPU::new(program);
+        assert_eq!(1, cpu.run().unwrap());
+        assert_eq!(vec![0], cpu.stack);
+        assert_eq!(Some(&[1][..]), cpu.locals(0));
+
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 5, 0),
+            Opcode::encode(Opcode::CALL, 3, Opcode::pack_call(0, 1)),
+            Opcode::encode(Opcode::HALT, 0, 0),
+
+            Opcode::encode(Opcode::LOAD, 0, 0),
+            Opcode::encode(Opcode::STORE, 3, 0),
+            Opcode::encode(Opcode::LOAD, 3, 0),
+            Opcode::encode(Opcode::RETURN, 1, 0),
+        ];
+        let mut cpu = CPU::new(program);
+        assert_eq!(5, cpu.run().unwrap());
+        assert_eq!(Some(&[][..]), cpu.locals(0));
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::LOAD, -1, 0)]);
+        assert_eq!(VmError::UnknownVariable(-1), cpu.run().unwrap_err().error);
+    }
+
+    #[test]
+    fn arity() {
+        // Calls a function taking 2 arguments with only 1 on the stack.
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::CALL, 3, Opcode::pack_call(0, 2)),
+            Opcode::encode(Opcode::HALT, 0, 0),
+            Opcode::encode(Opcode::RETURN, 0, 0),
+        ];
+        let fault = CPU::new(program).run().unwrap_err();
+        assert_eq!(VmError::ArityMismatch { expected: 2, found: 1 }, fault.error);
+        assert_eq!(1, fault.address);
+
+        // Returns 2 results from a function declared to return 1.
+        let program = vec![
+            Opcode::encode(Opcode::CALL, 2, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::DUP, 0, 0),
+            Opcode::encode(Opcode::RETURN, 1, 0),
+        ];
+        assert_eq!(VmError::ArityMismatch { expected: 1, found: 2 }, CPU::new(program).run().unwrap_err().error);
+
+        // Pops a value that belongs to the caller.
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::CALL, 3, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+            Opcode::encode(Opcode::POP, 0, 0),
+            Opcode::encode(Opcode::RETURN, 0, 0),
+        ];
+        assert_eq!(VmError::StackUnderflow, CPU::new(program).run().unwrap_err().error);
+    }
+
+    #[test]
+    fn wide_values() {
+        let mut program = Opcode::encode_push(5_000_000_000);
+        program.extend(Opcode::encode_push(-3));
+        program.push(Opcode::encode(Opcode::MUL, 0, 0));
+        program.extend(Opcode::encode_push_wide(7));
+        program.push(Opcode::encode(Opcode::ADD, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        let mut cpu = CPU::new(program);
+        assert_eq!(-14_999_999_993, cpu.run().unwrap());
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::PUSHW, 0, 0), 1]);
+        assert_eq!(VmError::PcOutOfBounds(2), cpu.run().unwrap_err().error);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut program = Opcode::encode_push(i64::MAX);
+        program.push(Opcode::encode(Opcode::PUSH, 1, 0));
+        program.push(Opcode::encode(Opcode::ADD, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        let mut cpu = CPU::new(program.clone());
+        assert_eq!(i64::MIN, cpu.run().unwrap());
+        assert!(cpu.overflow_flag());
+
+        let mut cpu = CPU::new(program.clone());
+        cpu.set_overflow_mode(OverflowMode::Saturating);
+        assert_eq!(i64::MAX, cpu.run().unwrap());
+        assert!(cpu.overflow_flag());
+
+        let mut cpu = CPU::new(program);
+        cpu.set_overflow_mode(OverflowMode::Checked);
+        let fault = cpu.run().unwrap_err();
+        assert_eq!(VmError::Overflow, fault.error);
+        assert_eq!(Some((Opcode::ADD, 0, 0)), fault.instruction);
+
+        let mut program = Opcode::encode_push(i64::MIN);
+        program.push(Opcode::encode(Opcode::PUSH, 2, 0));
+        program.push(Opcode::encode(Opcode::MUL, 0, 0));
+        program.push(Opcode::encode(Opcode::PUSH, 3, 0));
+        program.push(Opcode::encode(Opcode::SUB, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        let mut cpu = CPU::new(program);
+        cpu.set_overflow_mode(OverflowMode::Saturating);
+        assert_eq!(i64::MIN, cpu.run().unwrap());
+        assert!(cpu.overflow_flag());
+        assert!(!cpu.sign_flag());
+
+        // CMP's flags reflect the comparison even when the difference wraps around.
+        let mut program = Opcode::encode_push(i64::MIN);
+        program.push(Opcode::encode(Opcode::PUSH, 1, 0));
+        program.push(Opcode::encode(Opcode::CMP, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        let mut cpu = CPU::new(program);
+        assert_eq!(i64::MAX, cpu.run().unwrap());
+        assert!(cpu.overflow_flag());
+        assert!(!cpu.sign_flag());
+        assert!(!cpu.zero_flag());
+
+        // Every other arithmetic instruction clears the flag left by an overflowing ADD.
+        for opcode in [Opcode::DIV, Opcode::MOD, Opcode::FADD, Opcode::FDIV, Opcode::ITOF, Opcode::FTOI].iter() {
+            let mut program = Opcode::encode_push(i64::MAX);
+            program.push(Opcode::encode(Opcode::PUSH, 1, 0));
+            program.push(Opcode::encode(Opcode::ADD, 0, 0));
+            program.push(Opcode::encode(Opcode::PUSH, 1, 0));
+            program.push(Opcode::encode(*opcode, 0, 0));
+            program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+            let mut cpu = CPU::new(program);
+            cpu.run().unwrap();
+            assert!(!cpu.overflow_flag(), "{:?} left the overflow flag set", opcode);
+        }
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        fn run(ops: &[(Opcode, i16)]) -> Result<Vec<Value>, VmError> {
+            let mut program: Vec<u32> = (1..=4).map(|n| Opcode::encode(Opcode::PUSH, n, 0)).collect();
+            program.extend(ops.iter().map(|(opcode, operand1)| Opcode::encode(*opcode, *operand1, 0)));
+            program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+            let mut cpu = CPU::new(program);
+            while cpu.step().map_err(|fault| fault.error)? != StepOutcome::Halted {}
+            Ok(cpu.stack)
+        }
+
+        assert_eq!(Ok(vec![1, 2, 4, 3]), run(&[(Opcode::SWAP, 0)]));
+        assert_eq!(Ok(vec![1, 2, 3, 4, 3]), run(&[(Opcode::OVER, 0)]));
+        assert_eq!(Ok(vec![1, 3, 4, 2]), run(&[(Opcode::ROT, 0)]));
+        assert_eq!(Ok(vec![1, 2, 4]), run(&[(Opcode::NIP, 0)]));
+        assert_eq!(Ok(vec![1, 2, 4, 3, 4]), run(&[(Opcode::TUCK, 0)]));
+        assert_eq!(Ok(vec![1, 2, 3, 4, 1]), run(&[(Opcode::PICK, 3)]));
+        assert_eq!(Ok(vec![1, 3, 4, 2]), run(&[(Opcode::ROLL, 2)]));
+        assert_eq!(Ok(vec![1, 2, 3, 4]), run(&[(Opcode::ROLL, 0)]));
+        assert_eq!(Ok(vec![1]), run(&[(Opcode::DROP, 3)]));
+        assert_eq!(Ok(vec![1, 2, 3, 4]), run(&[(Opcode::DROP, 0)]));
+
+        assert_eq!(Err(VmError::StackUnderflow), run(&[(Opcode::PICK, 4)]));
+        assert_eq!(Err(VmError::StackUnderflow), run(&[(Opcode::ROLL, -1)]));
+        assert_eq!(Err(VmError::StackUnderflow), run(&[(Opcode::DROP, 5)]));
+        assert_eq!(Err(VmError::StackUnderflow), run(&[(Opcode::DROP, 3), (Opcode::SWAP, 0)]));
+    }
+
+    #[test]
+    fn bitwise() {
+        fn run(n2: i64, n1: i64, opcode: Opcode) -> i64 {
+            let mut program = Opcode::encode_push(n2);
+            program.extend(Opcode::encode_push(n1));
+            program.push(Opcode::encode(opcode, 0, 0));
+            program.push(Opcode::encode(Opcode::HALT, 0, 0));
+            CPU::new(program).run().unwrap()
+        }
+
+        assert_eq!(0b1000, run(0b1100, 0b1010, Opcode::AND));
+        assert_eq!(0b1110, run(0b1100, 0b1010, Opcode::OR));
+        assert_eq!(0b0110, run(0b1100, 0b1010, Opcode::XOR));
+        assert_eq!(!0b1010, run(0, 0b1010, Opcode::NOT));
+
+        assert_eq!(40, run(5, 3, Opcode::SHL));
+        assert_eq!(i64::MIN, run(1, 63, Opcode::SHL));
+        assert_eq!(0, run(1, 64, Opcode::SHL));
+        assert_eq!(-4, run(-16, 2, Opcode::SAR));
+        assert_eq!(-1, run(-16, 100, Opcode::SAR));
+        assert_eq!(0x3FFF_FFFF_FFFF_FFFC, run(-16, 2, Opcode::SHR));
+        assert_eq!(0, run(-16, -1, Opcode::SHR));
+
+        let mut cpu = CPU::new(vec![
+            Opcode::encode(Opcode::PUSH, 0b0101, 0),
+            Opcode::encode(Opcode::PUSH, 0b1010, 0),
+            Opcode::encode(Opcode::AND, 0, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+        ]);
+        assert_eq!(0, cpu.run().unwrap());
+        assert!(cpu.zero_flag());
+        assert!(!cpu.overflow_flag());
+    }
+
+    #[test]
+    fn memory() {
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 2, 0),
+            Opcode::encode(Opcode::GROW, 0, 0),
+            Opcode::encode(Opcode::POP, 0, 0),
+            Opcode::encode(Opcode::PUSH, 3, 0),
+            Opcode::encode(Opcode::PUSH, 0, 0),
+            Opcode::encode(Opcode::MLOAD, 0, 0),
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::MLOAD, 0, 0),
+            Opcode::encode(Opcode::ADD, 0, 0),
+            Opcode::encode(Opcode::MSTORE, 0, 0),
+            Opcode::encode(Opcode::MEMSIZE, 0, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+        ];
+
+        let mut cpu = CPU::new(program);
+        cpu.memory_mut().extend(vec![20, 22]);
+        assert_eq!(4, cpu.run().unwrap());
+        assert_eq!(&[20, 22, 0, 42], cpu.memory());
+
+        for address in [-1, 4].iter() {
+            let mut cpu = CPU::new(vec![
+                Opcode::encode(Opcode::PUSH, *address, 0),
+                Opcode::encode(Opcode::PUSH, 7, 0),
+                Opcode::encode(Opcode::MSTORE, 0, 0),
+            ]);
+            cpu.memory_mut().resize(4, 0);
+            assert_eq!(VmError::MemoryOutOfBounds(*address as Value), cpu.run().unwrap_err().error);
+        }
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::PUSH, 0, 0), Opcode::encode(Opcode::MLOAD, 0, 0)]);
+        assert_eq!(VmError::MemoryOutOfBounds(0), cpu.run().unwrap_err().error);
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::PUSH, -1, 0), Opcode::encode(Opcode::GROW, 0, 0)]);
+        assert_eq!(VmError::MemoryOutOfBounds(-1), cpu.run().unwrap_err().error);
+    }
+
+    #[test]
+    fn division_faults() {
+        for opcode in [Opcode::DIV, Opcode::MOD].iter() {
+            let program = vec![
+                Opcode::encode(Opcode::PUSH, 7, 0),
+                Opcode::encode(Opcode::PUSH, 0, 0),
+                Opcode::encode(*opcode, 0, 0),
+                Opcode::encode(Opcode::HALT, 0, 0),
+            ];
+
+            let fault = CPU::new(program).run().unwrap_err();
+            assert_eq!(VmError::DivisionByZero, fault.error);
+            assert_eq!(2, fault.address);
+            assert_eq!(Some((*opcode, 0, 0)), fault.instruction);
+        }
+
+        let mut program = Opcode::encode_push(i64::MIN);
+        program.push(Opcode::encode(Opcode::PUSH, -1, 0));
+        program.push(Opcode::encode(Opcode::DIV, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        let fault = CPU::new(program).run().unwrap_err();
+        assert_eq!(VmError::Overflow, fault.error);
+        assert_eq!(Some((Opcode::DIV, 0, 0)), fault.instruction);
+
+        let mut program = Opcode::encode_push(i64::MIN);
+        program.push(Opcode::encode(Opcode::PUSH, -1, 0));
+        program.push(Opcode::encode(Opcode::MOD, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        assert_eq!(0, CPU::new(program).run().unwrap());
+    }
+
+    #[test]
+    fn jump_modes() {
+        // Each branch sits at address 2 with operand1 = 1, so absolute lands on 1, forwards on 3
+        // and backwards on 1. JMP has always treated every mode other than 0 as forwards, and JLE
+        // every mode other than 2.
+        let cases = [
+            (Opcode::JMP, 0, 1), (Opcode::JMP, 1, 3), (Opcode::JMP, 2, 3),
+            (Opcode::JNE, 0, 1), (Opcode::JNE, 1, 3), (Opcode::JNE, 2, 1),
+            (Opcode::JLE, 0, 3), (Opcode::JLE, 1, 3), (Opcode::JLE, 2, 1), (Opcode::JLE, 3, 3),
+            (Opcode::CALL, Opcode::pack_call(0, 0), 1),
+            (Opcode::CALL, Opcode::pack_call(1, 0), 3),
+            (Opcode::CALL, Opcode::pack_call(2, 0), 1),
+        ];
+
+        for (opcode, mode, target) in cases.iter() {
+            let program = vec![
+                Opcode::encode(Opcode::PUSH, 0, 0),
+                Opcode::encode(Opcode::POP, 0, 0),
+                Opcode::encode(*opcode, 1, *mode),
+                Opcode::encode(Opcode::HALT, 0, 0),
+            ];
+            let mut cpu = CPU::new(program);
+            for _ in 0..3 {
+                cpu.step().unwrap();
+            }
+            assert_eq!(*target, cpu.current_address(), "{:?} with mode {}", opcode, mode);
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::ADD, 0, 0),
+        ];
+        let mut cpu = CPU::new(program);
+
+        let fault = cpu.run().unwrap_err();
+        assert_eq!(VmError::StackUnderflow, fault.error);
+        assert_eq!(1, fault.address);
+        assert_eq!(Some((Opcode::ADD, 0, 0)), fault.instruction);
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::RETURN, 0, 0)]);
+        assert_eq!(VmError::CallStackUnderflow, cpu.run().unwrap_err().error);
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::PUSH, 1, 0)]);
+        assert_eq!(VmError::PcOutOfBounds(1), cpu.run().unwrap_err().error);
+
+        // A CALL to nowhere faults before it takes its arguments or pushes a frame.
+        for target in [-1, 3].iter() {
+            let program = vec![
+                Opcode::encode(Opcode::PUSH, 4, 0),
+                Opcode::encode(Opcode::CALL, *target, Opcode::pack_call(0, 1)),
+                Opcode::encode(Opcode::HALT, 0, 0),
+            ];
+            let mut cpu = CPU::new(program);
+            assert_eq!(VmError::PcOutOfBounds(*target as i64), cpu.run().unwrap_err().error);
+            assert_eq!(vec![4], cpu.stack);
+            assert_eq!(1, cpu.call_stack.len());
+        }
     }
 }
 
 #[cfg(test)]
 mod test_lexer {
-    use super::*;
+    use crate::parser::diagnostic::*;
+    use crate::parser::lexer::*;
+    use crate::parser::tokens::*;
 
     #[test]
     fn lex_single_line() {
         let mut lexer = Lexer::new("PUSH 12");
-        lexer.lex();
+        lexer.lex().unwrap();
         assert_eq!(vec![
-                Token::new(TokenType::Str("PUSH".into()), 1),
-                Token::new(TokenType::Num(12), 1)
+                Token::with_span(TokenType::Str("PUSH".into()), 1, 1, 4),
+                Token::with_span(TokenType::Num(12), 1, 6, 2)
             ], 
             lexer.tokens);
     }
@@ -122,22 +515,121 @@ mod test_lexer {
     #[test]
     fn lex_multiple_lines() {
         let mut lexer = Lexer::new("Start:\n PUSH 12\nPUSH 15");
-        lexer.lex();
+        lexer.lex().unwrap();
         assert_eq!(vec![
-                Token::new(TokenType::Identifier("Start".into()), 1),
-                Token::new(TokenType::Str("PUSH".into()), 2),
-                Token::new(TokenType::Num(12), 2),
-                Token::new(TokenType::Str("PUSH".into()), 3),
-                Token::new(TokenType::Num(15), 3)
+                Token::with_span(TokenType::Identifier("Start".into()), 1, 1, 5),
+                Token::with_span(TokenType::Str("PUSH".into()), 2, 2, 4),
+                Token::with_span(TokenType::Num(12), 2, 7, 2),
+                Token::with_span(TokenType::Str("PUSH".into()), 3, 1, 4),
+                Token::with_span(TokenType::Num(15), 3, 6, 2)
             ], 
             lexer.tokens);
     }
 
+    #[test]
+    fn lex_comments() {
+        let mut lexer = Lexer::new("; header\nPUSH 1 // trailing\n/* block\n   comment */ POP ; done\n/* unterminated");
+        let diagnostics = lexer.lex().unwrap_err();
+
+        assert_eq!(vec![
+                Token::with_span(TokenType::Str("PUSH".into()), 2, 1, 4),
+                Token::with_span(TokenType::Num(1), 2, 6, 1),
+                Token::with_span(TokenType::Str("POP".into()), 4, 15, 3),
+            ],
+            lexer.tokens);
+        assert_eq!(vec![Diagnostic::new("<input>", 5, 1, 2, "Unterminated block comment")], diagnostics);
+    }
+
+    #[test]
+    fn lex_literals() {
+        let mut lexer = Lexer::new("-123 0x7F 0b1010 'a' '\\n' '\\'' -0x10");
+        lexer.lex().unwrap();
+
+        let values: Vec<TokenType> = lexer.tokens.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(vec![
+                TokenType::Num(-123),
+                TokenType::Num(0x7F),
+                TokenType::Num(0b1010),
+                TokenType::Num('a' as i64),
+                TokenType::Num('\n' as i64),
+                TokenType::Num('\'' as i64),
+                TokenType::Num(-16),
+            ],
+            values);
+
+        let mut lexer = Lexer::new("0x 12ab 'ab' '\\q' - 'c");
+        let messages: Vec<String> = lexer.lex().unwrap_err().into_iter().map(|d| d.message).collect();
+        assert_eq!(vec![
+                "Invalid number: 0x",
+                "Invalid number: 12ab",
+                "Character literal must contain a single character",
+                "Unknown escape sequence: \\q",
+                "Illegal character (-) encountered",
+                "Unterminated character literal",
+            ],
+            messages);
+    }
+
+    #[test]
+    fn lex_floats() {
+        let mut lexer = Lexer::new("1.5 -0.25 2.5e3 1.0E-2 7");
+        lexer.lex().unwrap();
+
+        let values: Vec<TokenType> = lexer.tokens.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(vec![
+                TokenType::Float(1.5),
+                TokenType::Float(-0.25),
+                TokenType::Float(2500.0),
+                TokenType::Float(0.01),
+                TokenType::Num(7),
+            ],
+            values);
+
+        let mut lexer = Lexer::new("1.5x 1.0e999 1.e 2.");
+        let messages: Vec<String> = lexer.lex().unwrap_err().into_iter().map(|d| d.message).collect();
+        assert_eq!(vec!["Invalid number: 1.5x", "Number out of range: 1.0e999", "Invalid number: 1.e", "Invalid number: 2."], messages);
+    }
+
+    #[test]
+    fn lex_errors() {
+        let mut lexer = Lexer::with_file("PUSH 1 0\n  POP # 0\n:\nPUSH 99999999999999999999 0", "test.nar");
+        let diagnostics = lexer.lex().unwrap_err();
+
+        assert_eq!(vec![
+                Diagnostic::new("test.nar", 2, 7, 1, "Illegal character (#) encountered"),
+                Diagnostic::new("test.nar", 3, 1, 1, "Colon encountered outside of identifier"),
+                Diagnostic::new("test.nar", 4, 6, 20, "Number out of range: 99999999999999999999"),
+            ],
+            diagnostics);
+        assert_eq!("error: Illegal character (#) encountered\n --> test.nar:2:7\n  |\n2 |   POP # 0\n  |       ^\n",
+            diagnostics[0].render("PUSH 1 0\n  POP # 0"));
+    }
+
+    #[test]
+    fn lex_unterminated_char_at_end_of_line() {
+        // The newline still ends the line, so later errors point at the right place.
+        let mut lexer = Lexer::new("PUSH '\nPUSH '\\\nPUSH #");
+        let diagnostics = lexer.lex().unwrap_err();
+
+        assert_eq!(vec![
+                Diagnostic::new("<input>", 1, 6, 1, "Unterminated character literal"),
+                Diagnostic::new("<input>", 2, 6, 2, "Unterminated character literal"),
+                Diagnostic::new("<input>", 3, 6, 1, "Illegal character (#) encountered"),
+            ],
+            diagnostics);
+    }
 }
 
 #[cfg(test)]
 mod test_parsing {
-    use super::*;
+    use crate::vm::cpu::*;
+    use crate::vm::error::*;
+    use crate::vm::instruction::*;
+    use crate::parser::assembler::*;
+    use crate::parser::diagnostic::*;
+    use crate::parser::lexer::*;
+    use crate::parser::reader::*;
+    use crate::parser::tokens::*;
 
     #[test]
     fn assemble() {
@@ -152,11 +644,267 @@ mod test_parsing {
         let code = vec![Opcode::encode(Opcode::PUSH, 12, 0),
                 Opcode::encode(Opcode::PUSH, 15, 0)];
         let mut assembler = Assembler::new(tokens, "");
-        assembler.assemble();
+        assembler.assemble().unwrap();
         
         assert_eq!(code, assembler.output);
     }
 
+    #[test]
+    fn assemble_optional_operands() {
+        let mut lexer = Lexer::new("JMP end\nback: ADD\nPUSH 5\nSTDOUT 3\nJNE back 0\nJE 2 1\nend: HALT");
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+
+        assert_eq!(vec![
+                Opcode::encode(Opcode::JMP, 6, 0),
+                Opcode::encode(Opcode::ADD, 0, 0),
+                Opcode::encode(Opcode::PUSH, 5, 0),
+                Opcode::encode(Opcode::STDOUT, 0, 3),
+                Opcode::encode(Opcode::JNE, 1, 0),
+                Opcode::encode(Opcode::JE, 2, 1),
+                Opcode::encode(Opcode::HALT, 0, 0),
+            ],
+            assembler.output);
+        assert_eq!(Some(&6), assembler.labels.get("end"));
+    }
+
+    #[test]
+    fn assemble_jle_labels() {
+        // JLE has no absolute mode, so its labels become offsets in whichever direction it jumps.
+        let mut lexer = Lexer::new("back: PUSH 1\nJLE end\nJLE back 2\nJNE end\nend: HALT");
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+        assert_eq!(vec![
+                Opcode::encode(Opcode::PUSH, 1, 0),
+                Opcode::encode(Opcode::JLE, 3, 0),
+                Opcode::encode(Opcode::JLE, 2, 2),
+                Opcode::encode(Opcode::JNE, 4, 0),
+                Opcode::encode(Opcode::HALT, 0, 0),
+            ],
+            assembler.output);
+    }
+
+    #[test]
+    fn assemble_padded_operands() {
+        let source = "PUSH 12 0\nPUSH 'a' 0\nADD 0 0\nSTDOUT 0 3\nLOAD 1 0\nJNE 8 2\nRETURN 1 0\nHALT 0 0";
+        let mut lexer = Lexer::new(source);
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+        assert_eq!(vec![
+                Opcode::encode(Opcode::PUSH, 12, 0),
+                Opcode::encode(Opcode::PUSH, 97, 0),
+                Opcode::encode(Opcode::ADD, 0, 0),
+                Opcode::encode(Opcode::STDOUT, 0, 3),
+                Opcode::encode(Opcode::LOAD, 1, 0),
+                Opcode::encode(Opcode::JNE, 8, 2),
+                Opcode::encode(Opcode::RETURN, 1, 0),
+                Opcode::encode(Opcode::HALT, 0, 0),
+            ],
+            assembler.output);
+
+        let mut lexer = Lexer::new("PUSH 1 200\nPOP 40000 0\nHALT 0 0 0");
+        lexer.lex().unwrap();
+
+        let messages: Vec<String> = Assembler::new(lexer.tokens, "").assemble().unwrap_err().into_iter().map(|d| d.message).collect();
+        assert_eq!(vec![
+                "2nd operand out of range (-128..=127): 200",
+                "1st operand out of range (-32768..=32767): 40000",
+                "HALT takes at most 0 operand(s)",
+            ],
+            messages);
+    }
+
+    #[test]
+    fn assemble_calls() {
+        // The mode comes before the argument count, so `CALL 3 2` is still a backwards call.
+        let mut lexer = Lexer::new("CALL f\nCALL f 0 2\nCALL 3 1 1\nCALL 3 2\nf: RETURN\nRETURN 2\nCALL f 0 64\nCALL f 4");
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        let messages: Vec<String> = assembler.assemble().unwrap_err().into_iter().map(|d| d.message).collect();
+        assert_eq!(vec!["Argument count out of range (0..=63): 64", "CALL's mode out of range (0..=3): 4"], messages);
+
+        assert_eq!(vec![
+                Opcode::encode(Opcode::CALL, 4, 0),
+                Opcode::encode(Opcode::CALL, 4, Opcode::pack_call(0, 2)),
+                Opcode::encode(Opcode::CALL, 3, Opcode::pack_call(1, 1)),
+                Opcode::encode(Opcode::CALL, 3, Opcode::pack_call(2, 0)),
+                Opcode::encode(Opcode::RETURN, 0, 0),
+                Opcode::encode(Opcode::RETURN, 2, 0),
+            ],
+            assembler.output);
+        assert_eq!((1, 1), Opcode::unpack_call(assembler.output[2] as i8));
+    }
+
+    #[test]
+    fn assemble_slot_counts() {
+        let source = "
+            ENTER
+            PUSH 1
+            STORE 0
+            CALL f 0 1
+            HALT
+        f:
+            ENTER
+            LOAD 0
+            STORE 3
+            RETURN
+        g:
+            ENTER 5
+            LOAD 7
+            RETURN
+        h:
+            ENTER
+            RETURN
+        ";
+        let mut lexer = Lexer::new(source);
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+
+        let enters: Vec<u32> = assembler.output.iter().copied().filter(|i| Opcode::decode(*i).0 == Opcode::ENTER).collect();
+        assert_eq!(vec![
+                Opcode::encode(Opcode::ENTER, 1, 0),
+                Opcode::encode(Opcode::ENTER, 4, 0),
+                Opcode::encode(Opcode::ENTER, 5, 0),
+                Opcode::encode(Opcode::ENTER, 0, 0),
+            ],
+            enters);
+    }
+
+    #[test]
+    fn assemble_wide_push() {
+        let mut lexer = Lexer::new("PUSH 100000\nPUSHW 1\nJMP end\nend: HALT");
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+
+        let mut code = Opcode::encode_push_wide(100000);
+        code.extend(Opcode::encode_push_wide(1));
+        code.push(Opcode::encode(Opcode::JMP, 7, 0));
+        code.push(Opcode::encode(Opcode::HALT, 0, 0));
+        assert_eq!(code, assembler.output);
+    }
+
+    #[test]
+    fn assemble_globals() {
+        let source = "
+            .global total
+            .global count
+            PUSH 0
+            GSTORE total
+            PUSH 3
+            GSTORE count
+        loop:
+            GLOAD count
+            CALL add 0 1
+            GLOAD count
+            PUSH 1
+            SUB
+            GSTORE count
+            GLOAD count
+            PUSH 0
+            CMP
+            POP
+            JNE loop
+            GLOAD total
+            HALT
+
+        add:
+            LOAD 0
+            GLOAD total
+            ADD
+            GSTORE total
+            RETURN
+        ";
+        let mut lexer = Lexer::new(source);
+        lexer.lex().unwrap();
+        assert_eq!(TokenType::Directive("global".into()), lexer.tokens[0].token_type);
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+        assert_eq!(Some(&1), assembler.globals.get("count"));
+        assert_eq!(Opcode::encode(Opcode::GSTORE, 1, 0), assembler.output[3]);
+
+        let mut cpu = CPU::new(assembler.output);
+        assert_eq!(6, cpu.run().unwrap());
+        assert_eq!(Some(&0), cpu.globals().get(&1));
+
+        let fault = CPU::new(vec![Opcode::encode(Opcode::GLOAD, 5, 0)]).run().unwrap_err();
+        assert_eq!(VmError::UnknownGlobal(5), fault.error);
+        assert_eq!("global 5 has not been set at instruction 0 ((GLOAD, 5, 0))", fault.to_string());
+
+        let mut lexer = Lexer::new(".global a\n.global a\n.global\n.local b\nGLOAD b\nGSTORE 2");
+        lexer.lex().unwrap();
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        let messages: Vec<String> = assembler.assemble().unwrap_err().into_iter().map(|d| d.message).collect();
+        assert_eq!(vec![
+                "Global defined more than once: a",
+                "Expected a name after .global",
+                "Unknown directive: .local",
+                "Illegal opcode encountered: b",
+                "Global doesn't exist: b",
+            ],
+            messages);
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let source = "start:\nPUSH 1\nJMP nowhere\nPUSHX 2 0\nHALT 0\nLOAD";
+        let mut lexer = Lexer::with_file(source, "test.nar");
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::with_source_file(lexer.tokens, "", "test.nar");
+        let diagnostics = assembler.assemble().unwrap_err();
+
+        assert_eq!(vec![
+                Diagnostic::new("test.nar", 3, 5, 7, "Identifier doesn't exist: nowhere"),
+                Diagnostic::new("test.nar", 4, 1, 5, "Illegal opcode encountered: PUSHX"),
+                Diagnostic::new("test.nar", 5, 6, 1, "HALT takes at most 0 operand(s)"),
+                Diagnostic::new("test.nar", 6, 1, 4, "Expected Variable operand after LOAD"),
+            ],
+            diagnostics);
+        assert_eq!("error: Identifier doesn't exist: nowhere\n --> test.nar:3:5\n  |\n3 | JMP nowhere\n  |     ^^^^^^^\n",
+            diagnostics[0].render(source));
+    }
+
+    #[test]
+    fn operand_ranges() {
+        let mut lexer = Lexer::new("PUSH -1\nJMP '>' -2\nLOAD 40000\nSTDOUT 200\nPICK -1\nROLL 2");
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        let messages: Vec<String> = assembler.assemble().unwrap_err().into_iter().map(|d| d.message).collect();
+        assert_eq!(vec![
+                "1st operand out of range (-32768..=32767): 40000",
+                "2nd operand out of range (-128..=127): 200",
+                "1st operand out of range (0..=32767): -1",
+            ],
+            messages);
+        assert_eq!(vec![
+                Opcode::encode(Opcode::PUSH, -1, 0),
+                Opcode::encode(Opcode::JMP, 62, -2),
+                Opcode::encode(Opcode::ROLL, 2, 0),
+            ],
+            assembler.output);
+
+        let mut lexer = Lexer::new(format!("JMP end\n{}end:\nHALT", "POP\n".repeat(40000)));
+        lexer.lex().unwrap();
+
+        let diagnostics = Assembler::new(lexer.tokens, "").assemble().unwrap_err();
+        assert_eq!(1, diagnostics.len());
+        assert_eq!("Address out of range (0..=32767): end is at 40001", diagnostics[0].message);
+        assert_eq!((1, 5), (diagnostics[0].line, diagnostics[0].column));
+    }
+
     #[test]
     fn write_to_file() {
         let tokens = vec![Token::new(TokenType::Identifier("Start".into()), 1),
@@ -171,8 +919,8 @@ mod test_parsing {
                 Token::new(TokenType::Num(0), 4),];
 
         let mut assembler = Assembler::new(tokens, "binaries/test1.bin");
-        assembler.assemble();
-        assembler.write();
+        assembler.assemble().unwrap();
+        assembler.write().unwrap();
     }
 
     #[test]
@@ -180,14 +928,423 @@ mod test_parsing {
         let instructions = vec![Opcode::encode(Opcode::PUSH, 12, 0),
                             Opcode::encode(Opcode::POP, 0, 0),
                             Opcode::encode(Opcode::HALT, 0, 0)];
-        assert_eq!(instructions, Reader::read("binaries/test1.bin"));
+        assert_eq!(instructions, Reader::read("binaries/test1.bin").unwrap());
+    }
+
+    #[test]
+    fn read_from_bytes() {
+        let instructions = vec![Opcode::encode(Opcode::PUSH, 12, 0), Opcode::encode(Opcode::HALT, 0, 0)];
+        let bytes: Vec<u8> = instructions.iter().flat_map(|i| Opcode::instruction_to_byte_array(*i).to_vec()).collect();
+
+        assert_eq!(instructions, Reader::from_bytes(&bytes).unwrap());
+        assert_eq!(instructions, Reader::from_reader(&bytes[..]).unwrap());
+        assert_eq!(Vec::<u32>::new(), Reader::from_bytes(&[]).unwrap());
+
+        match Reader::from_bytes(&bytes[..7]) {
+            Err(LoadError::TruncatedInstruction(7)) => (),
+            other => panic!("expected a truncated instruction, got {:?}", other),
+        }
+        match Reader::read("binaries/missing.bin") {
+            Err(LoadError::Io(e)) => assert_eq!(std::io::ErrorKind::NotFound, e.kind()),
+            other => panic!("expected an io error, got {:?}", other),
+        }
     }
 
     #[test]
     fn execute_file() {
-        let instructions = Reader::read("binaries/test1.bin");
+        let instructions = Reader::read("binaries/test1.bin").unwrap();
 
         let mut cpu = CPU::new(instructions);
-        cpu.run();
+        assert_eq!(0, cpu.run().unwrap());
+    }
+}
+#[cfg(test)]
+mod test_host {
+    use crate::vm::cpu::*;
+    use crate::vm::error::*;
+    use crate::vm::host::*;
+    use crate::vm::instruction::*;
+    use crate::parser::assembler::*;
+    use crate::parser::lexer::*;
+    use std::fs::read_to_string;
+
+    fn assemble(file_path: &str) -> Vec<u32> {
+        let mut lexer = Lexer::new(read_to_string(file_path).unwrap());
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+        assembler.output
+    }
+
+    #[test]
+    fn capture_output() {
+        let mut cpu = CPU::with_host(assemble("nar_files/fizz_buzz.nar"), BufferedHost::new());
+        assert_eq!(0, cpu.run().unwrap());
+        assert!(cpu.host().output.starts_with("1\n2\nb \n4\nf \nb \n7\n8\nb \nf \n11\nb \n13\n14\nfb \n16\n"));
+    }
+
+    #[test]
+    fn feed_input() {
+        let host = BufferedHost::with_input(vec!["50", "150", "100"]);
+        let mut cpu = CPU::with_host(assemble("nar_files/guessing_game.nar"), host);
+        cpu.run().unwrap();
+        assert_eq!(">u\n>o\n>", cpu.host().output);
+
+        let host = BufferedHost::with_input(vec!["3000000000", "3000000000"]);
+        let mut cpu = CPU::with_host(vec![
+            Opcode::encode(Opcode::STDIN, 0, 0),
+            Opcode::encode(Opcode::STDIN, 0, 0),
+            Opcode::encode(Opcode::ADD, 0, 0),
+            Opcode::encode(Opcode::STDOUT, 0, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+        ], host);
+        assert_eq!(6_000_000_000, cpu.run().unwrap());
+        assert_eq!("6000000000\n", cpu.host().output);
+
+        let mut cpu = CPU::with_host(assemble("nar_files/guessing_game.nar"), BufferedHost::with_input(vec!["abc"]));
+        assert_eq!(VmError::InputParse("abc".into()), cpu.run().unwrap_err().error);
+    }
+
+    #[test]
+    fn floats() {
+        let mut lexer = Lexer::new("
+            PUSH 1.5
+            PUSH 3
+            ITOF
+            FMUL
+            STDOUT 4
+            PUSH -0.5
+            FDIV
+            STDOUT 5
+            PUSH 2.25
+            FCMP
+            FTOI
+            STDOUT
+        ");
+        lexer.lex().unwrap();
+        let mut assembler = Assembler::new(lexer.tokens, "");
+        assembler.assemble().unwrap();
+        assembler.output.push(Opcode::encode(Opcode::HALT, 0, 0));
+
+        let mut cpu = CPU::with_host(assembler.output, BufferedHost::new());
+        assert_eq!(-11, cpu.run().unwrap());
+        assert_eq!("4.5\n-9.0-11\n", cpu.host().output);
+        assert!(!cpu.sign_flag());
+        assert!(!cpu.zero_flag());
+
+        let mut program = Opcode::encode_push_wide(float_to_value(f64::NAN));
+        program.push(Opcode::encode(Opcode::FTOI, 0, 0));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+        assert_eq!(0, CPU::new(program).run().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod test_debugger {
+    use crate::vm::cpu::*;
+    use crate::vm::debugger::*;
+    use crate::vm::instruction::*;
+
+    fn program() -> Vec<u32> {
+        vec![
+            Opcode::encode(Opcode::PUSH, 10, 0),
+            Opcode::encode(Opcode::PUSH, 5, 0),
+            Opcode::encode(Opcode::CALL, 4, Opcode::pack_call(0, 2)),
+            Opcode::encode(Opcode::HALT, 0, 0),
+
+            Opcode::encode(Opcode::LOAD, 0, 0),
+            Opcode::encode(Opcode::STORE, 1, 0),
+            Opcode::encode(Opcode::LOAD, 1, 0),
+            Opcode::encode(Opcode::RETURN, 1, 0),
+        ]
+    }
+
+    #[test]
+    fn step() {
+        let mut debugger = Debugger::new(CPU::new(program()));
+        assert_eq!(StopReason::Step, debugger.step().unwrap());
+        assert_eq!(1, debugger.cpu.current_address());
+        assert_eq!(vec![10], debugger.cpu.stack);
+
+        debugger.step().unwrap();
+        debugger.step().unwrap();
+        assert_eq!(4, debugger.cpu.current_address());
+        assert_eq!(2, debugger.cpu.call_stack.len());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.add_breakpoint(6);
+        debugger.add_conditional_breakpoint(3, Condition::Equal(11));
+
+        assert_eq!(StopReason::Breakpoint(6), debugger.run_until_breakpoint().unwrap());
+        assert!(debugger.cpu.stack.is_empty());
+        assert_eq!(StopReason::Halted(10), debugger.run_until_breakpoint().unwrap());
+
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.add_conditional_breakpoint(3, Condition::Equal(10));
+        assert_eq!(StopReason::Breakpoint(3), debugger.run_until_breakpoint().unwrap());
+
+        // A breakpoint on the first instruction stops before it runs, but only the first time.
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.add_breakpoint(0);
+        debugger.add_breakpoint(1);
+        assert_eq!(StopReason::Breakpoint(0), debugger.run_until_breakpoint().unwrap());
+        assert!(debugger.cpu.stack.is_empty());
+        assert_eq!(StopReason::Breakpoint(1), debugger.run_until_breakpoint().unwrap());
+
+        // Nor does resuming stop again on a breakpoint that a step just landed on.
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.add_breakpoint(1);
+        debugger.step().unwrap();
+        assert_eq!(StopReason::Halted(10), debugger.run_until_breakpoint().unwrap());
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.step().unwrap();
+        debugger.step().unwrap();
+        assert_eq!(StopReason::Step, debugger.step_over().unwrap());
+        assert_eq!(3, debugger.cpu.current_address());
+        assert_eq!(1, debugger.cpu.call_stack.len());
+
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.add_breakpoint(5);
+        debugger.run_until_breakpoint().unwrap();
+        assert_eq!(StopReason::Step, debugger.step_out().unwrap());
+        assert_eq!(3, debugger.cpu.current_address());
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut debugger = Debugger::new(CPU::new(program()));
+        debugger.add_watchpoint(1, 1);
+        let watchpoint = Watchpoint { depth: 1, variable: 1 };
+
+        // The argument moved into the frame by CALL counts as a change.
+        let reason = debugger.run_until_breakpoint().unwrap();
+        assert_eq!(StopReason::Watchpoint { watchpoint, old: None, new: Some(5) }, reason);
+        assert_eq!(4, debugger.cpu.current_address());
+
+        let reason = debugger.run_until_breakpoint().unwrap();
+        assert_eq!(StopReason::Watchpoint { watchpoint, old: Some(5), new: Some(10) }, reason);
+        assert_eq!(6, debugger.cpu.current_address());
+    }
+}
+
+#[cfg(test)]
+mod test_limits {
+    use crate::vm::cpu::*;
+    use crate::vm::error::*;
+    use crate::vm::instruction::*;
+    use crate::vm::limits::*;
+    use std::time::Instant;
+
+    #[test]
+    fn instruction_budget() {
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::JMP, 0, 0)]);
+        cpu.set_limits(Limits { max_instructions: Some(100), ..Limits::unlimited() });
+
+        let fault = cpu.run().unwrap_err();
+        assert_eq!(VmError::InstructionLimitExceeded(100), fault.error);
+        assert_eq!(100, fault.executed);
+        assert_eq!(100, cpu.instructions_executed());
+    }
+
+    #[test]
+    fn deadline() {
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::JMP, 0, 0)]);
+        cpu.set_limits(Limits { deadline: Some(Instant::now()), ..Limits::unlimited() });
+        assert_eq!(VmError::DeadlineExceeded, cpu.run().unwrap_err().error);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn stack_depth() {
+        let mut cpu = CPU::new(vec![
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::JMP, 0, 0),
+        ]);
+        cpu.set_limits(Limits { max_stack_depth: Some(64), ..Limits::unlimited() });
+
+        let fault = cpu.run().unwrap_err();
+        assert_eq!(VmError::StackOverflow(64), fault.error);
+        assert_eq!(0, fault.address);
+
+        let mut cpu = CPU::new(vec![Opcode::encode(Opcode::CALL, 0, 0)]);
+        cpu.set_limits(Limits { max_call_depth: Some(10), ..Limits::unlimited() });
+        assert_eq!(VmError::CallStackOverflow(10), cpu.run().unwrap_err().error);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut cpu = CPU::new(vec![
+            Opcode::encode(Opcode::PUSH, 16, 0),
+            Opcode::encode(Opcode::GROW, 0, 0),
+            Opcode::encode(Opcode::JMP, 0, 0),
+        ]);
+        cpu.set_limits(Limits { max_memory: Some(40), ..Limits::unlimited() });
+
+        let fault = cpu.run().unwrap_err();
+        assert_eq!(VmError::MemoryLimitExceeded(40), fault.error);
+        assert_eq!(1, fault.address);
+        assert_eq!(32, cpu.memory().len());
+
+        let mut program = Opcode::encode_push(1 << 60);
+        program.push(Opcode::encode(Opcode::GROW, 0, 0));
+
+        // Without a limit set, the heap still can't grow past DEFAULT_MAX_MEMORY.
+        let fault = CPU::new(program.clone()).run().unwrap_err();
+        assert_eq!(VmError::MemoryLimitExceeded(DEFAULT_MAX_MEMORY), fault.error);
+
+        // And with a limit bigger than anything the allocator can give, it faults rather than panics.
+        let mut cpu = CPU::new(program);
+        cpu.set_limits(Limits { max_memory: Some(usize::MAX), ..Limits::unlimited() });
+        assert_eq!(VmError::MemoryLimitExceeded(usize::MAX), cpu.run().unwrap_err().error);
+        assert!(cpu.memory().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test_trace {
+    use crate::vm::cpu::*;
+    use crate::vm::instruction::*;
+    use crate::vm::trace::*;
+
+    fn program() -> Vec<u32> {
+        vec![
+            Opcode::encode(Opcode::PUSH, 2, 0),
+            Opcode::encode(Opcode::PUSH, 5, 0),
+            Opcode::encode(Opcode::SUB, 0, 0),
+            Opcode::encode(Opcode::HALT, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn vec_trace() {
+        let trace = VecTrace::new();
+        let mut cpu = CPU::new(program());
+        cpu.set_tracer(Box::new(trace.clone()));
+        cpu.run().unwrap();
+
+        let events = trace.events();
+        assert_eq!(4, events.len());
+        assert_eq!(TraceEvent {
+            address: 2,
+            instruction: (Opcode::SUB, 0, 0),
+            stack_before: vec![2, 5],
+            stack_after: vec![-3],
+            zero_flag: false,
+            sign_flag: false,
+            overflow_flag: false,
+        }, events[2]);
+    }
+
+    #[test]
+    fn text_trace() {
+        let mut sink = TextTrace::new(Vec::new());
+        sink.record(&TraceEvent {
+            address: 12,
+            instruction: (Opcode::STORE, 1, 0),
+            stack_before: vec![3, 3],
+            stack_after: vec![3],
+            zero_flag: false,
+            sign_flag: true,
+            overflow_flag: false,
+        });
+        assert_eq!("   12  STORE 1 0  [3, 3] -> [3]  z=0 s=1 o=0\n", String::from_utf8(sink.into_inner()).unwrap());
+    }
+
+    #[test]
+    fn binary_trace() {
+        let trace = VecTrace::new();
+        let mut cpu = CPU::new(program());
+        cpu.set_tracer(Box::new(trace.clone()));
+        cpu.run().unwrap();
+
+        let mut sink = BinaryTrace::new(Vec::new());
+        trace.events().iter().for_each(|e| sink.record(e));
+        let bytes = sink.into_inner();
+
+        assert_eq!(Some(trace.events()), BinaryTrace::decode(&bytes));
+        assert_eq!(None, BinaryTrace::decode(&bytes[..bytes.len() - 1]));
+    }
+}
+
+#[cfg(test)]
+mod test_disassembler {
+    use crate::vm::instruction::*;
+    use crate::parser::assembler::*;
+    use crate::parser::disassembler::*;
+    use crate::parser::lexer::*;
+    use crate::parser::reader::*;
+    use std::fs::read_to_string;
+
+    fn assemble<S: Into<String>>(source: S, file_path: &str) -> Assembler {
+        let mut lexer = Lexer::new(source);
+        lexer.lex().unwrap();
+
+        let mut assembler = Assembler::new(lexer.tokens, file_path);
+        assembler.assemble().unwrap();
+        assembler
+    }
+
+    #[test]
+    fn synthesized_labels() {
+        let program = vec![
+            Opcode::encode(Opcode::PUSH, 1, 0),
+            Opcode::encode(Opcode::JMP, 3, 0),
+            Opcode::encode(Opcode::JNE, 1, 2),
+            Opcode::encode(Opcode::HALT, 0, 0),
+        ];
+
+        assert_eq!("    PUSH    1\n\nlabel_1:\n    JMP     label_3\n    JNE     1 2\n\nlabel_3:\n    HALT\n",
+            Disassembler::new(&program).disassemble());
+    }
+
+    #[test]
+    fn wide_push() {
+        let assembler = assemble("PUSH -5000000000\nloop: JMP loop", "");
+        let text = Disassembler::new(&assembler.output).disassemble();
+        assert_eq!("    PUSHW   -5000000000\n\nlabel_3:\n    JMP     label_3\n", text);
+        assert_eq!(assembler.output, assemble(text, "").output);
+    }
+
+    #[test]
+    fn targets_between_instructions() {
+        // Jumps into a PUSHW's value and a symbol past the end have nowhere to put a label.
+        let mut program = vec![Opcode::encode(Opcode::JMP, 2, 0)];
+        program.extend(Opcode::encode_push_wide(1 << 40));
+        program.push(Opcode::encode(Opcode::HALT, 0, 0));
+        let mut symbols = std::collections::HashMap::new();
+        symbols.insert("beyond".to_string(), 10);
+
+        let disassembler = Disassembler::with_symbols(&program, &symbols);
+        let text = disassembler.disassemble();
+        assert_eq!("    JMP     2
+    PUSHW   1099511627776
+    HALT
+", text);
+        assert!(disassembler.labels_at(2).is_empty());
+        assert_eq!(program, assemble(text, "").output);
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = read_to_string("nar_files/fizz_buzz.nar").unwrap();
+        let assembler = assemble(source, "binaries/round_trip.bin");
+        assembler.write().unwrap();
+        assembler.write_symbols().unwrap();
+
+        let program = Reader::read("binaries/round_trip.bin").unwrap();
+        let symbols = Reader::read_symbols("binaries/round_trip.sym").unwrap();
+        assert_eq!(assembler.labels, symbols);
+
+        let text = Disassembler::with_symbols(&program, &symbols).disassemble();
+        let reassembled = assemble(text.clone(), "");
+        assert_eq!(program, reassembled.output);
+        assert_eq!(text, Disassembler::with_symbols(&reassembled.output, &reassembled.labels).disassemble());
+    }
+}
@@ -1,88 +1,309 @@
 use crate::parser::tokens::*;
-use crate::vm::instruction::Opcode;
+use crate::parser::diagnostic::*;
+use crate::vm::instruction::{Opcode, OperandKind};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 
 pub struct Assembler {
     source: Vec<Token>,
+    // The next token to assemble.
+    index: usize,
     pub output: Vec<u32>,
+    pub labels: HashMap<String, usize>,
+    /// The number given to each name declared with `.global`, in the order they were declared.
+    pub globals: HashMap<String, i16>,
 
-    file_path: String
+    file_path: String,
+    source_file: String
 }
 
 impl Assembler {
     pub fn new<S: Into<String>>(source: Vec<Token>, file_path: S) -> Assembler {
-        Assembler { source, output: Vec::new(), file_path: file_path.into() }
+        Assembler::with_source_file(source, file_path, "<input>")
     }
 
-    pub fn assemble(&mut self) {
-        let mut identifiers: HashMap<String, usize> = HashMap::new();
+    /// Like `new`, but diagnostics name `source_file` as the file the tokens came from.
+    pub fn with_source_file<S: Into<String>, F: Into<String>>(source: Vec<Token>, file_path: S, source_file: F) -> Assembler {
+        Assembler { source, index: 0, output: Vec::new(), labels: HashMap::new(), globals: HashMap::new(), file_path: file_path.into(), source_file: source_file.into() }
+    }
+
+    /// Assembles every instruction, carrying on past errors so they can all be reported at once.
+    /// Each opcode is followed by the operands `Opcode::operands` lists for it, and optional
+    /// operands can be left out. An `ENTER` without a count reserves enough slots for every
+    /// variable used between it and the next `ENTER`.
+    pub fn assemble(&mut self) -> Result<(), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        // The `ENTER` waiting for its slot count, and how many slots it needs so far.
+        let mut enter: Option<(usize, i16)> = None;
+        // Label and global operands, resolved once every name has been seen.
+        let mut fixups: Vec<(usize, OperandKind, Token)> = Vec::new();
 
-        let mut index = 0;
-        loop {
-            if let TokenType::Identifier(i) = self.source[index].token_type.clone() {
-                identifiers.insert(i, index / 3);
-                self.source.remove(index);
+        while let Some(current_token) = self.advance() {
+            let val = match current_token.token_type.clone() {
+                TokenType::Identifier(i) => {
+                    if self.labels.insert(i.clone(), self.output.len()).is_some() {
+                        diagnostics.push(self.error(&current_token, format!("Identifier defined more than once: {}", i)));
+                    }
+                    continue;
+                },
+                TokenType::Directive(d) => {
+                    if let Err(diagnostic) = self.directive(&d, &current_token) {
+                        diagnostics.push(diagnostic);
+                    }
+                    continue;
+                },
+                TokenType::Str(val) => val,
+                TokenType::Num(_) | TokenType::Float(_) => {
+                    diagnostics.push(self.error(&current_token, "Number encountered outside of being an operand or as an extra operand"));
+                    continue;
+                }
+            };
+
+            let opcode = Opcode::from(&val);
+            if opcode == Opcode::ILG {
+                diagnostics.push(self.error(&current_token, format!("Illegal opcode encountered: {}", val)));
+                self.skip_operands();
                 continue;
             }
-            index += 1;
-            if index >= self.source.len() {
-                break;
-            }
-        }
 
-        loop {
-            let current_token = self.source.remove(0);
-            match current_token.token_type {
-                TokenType::Identifier(_) => unreachable!(),
-                TokenType::Str(val) => {
-                    let opcode = Opcode::from(&val);
-                    if opcode == Opcode::ILG {
-                        panic!("Illegal opcode encountered: {}", val);
+            let mut operand1: i16 = 0;
+            let mut operand2: i8 = 0;
+            let mut immediate: Option<i64> = None;
+            let mut float: Option<f64> = None;
+            let mut arguments: u8 = 0;
+            let mut fixup: Option<(OperandKind, Token)> = None;
+            let mut given = 0;
+            let mut valid = true;
+
+            // Two operands where the opcode takes fewer means the old, padded form.
+            let padded = opcode.padded_operands();
+            let kinds = match self.source.get(self.index + 1).map(|t| &t.token_type) {
+                Some(TokenType::Num(_)) if padded.len() > opcode.operands().len() && Assembler::is_operand(self.peek(), padded[0]) => padded,
+                _ => opcode.operands(),
+            };
+
+            for kind in kinds {
+                if !Assembler::is_operand(self.peek(), *kind) {
+                    if !kind.is_optional() {
+                        diagnostics.push(self.error(&current_token, format!("Expected {:?} operand after {}", kind, val)));
+                        valid = false;
                     }
+                    break;
+                }
 
-                    let operand2 = match self.source.remove(1).token_type {
-                        TokenType::Identifier(_) => panic!("Identifier encountered as an operand: {:?}", self.source[1]),
-                        TokenType::Str(s) => panic!("String encountered as 2nd operand: {:?}", self.source[1]),
-                        TokenType::Num(n) => n as i8
-                    };
-
-                    match self.source.remove(0).token_type {
-                        TokenType::Identifier(_) => unreachable!(),
-                        TokenType::Str(s) => {
-                            match opcode {
-                                Opcode::JMP | Opcode::JE | Opcode::JNE| Opcode::JL | Opcode::JG | Opcode::JLE | Opcode::JGE | Opcode::CALL => {
-                                    let operand1 = match identifiers.get(&s) {
-                                        Some(i) => i,
-                                        None => panic!("Identifier doesn't exist")
-                                    };
-
-                                    self.output.push(Opcode::encode(opcode, *operand1 as i16, operand2))
-                                },
-
-                                Opcode::PUSH => {
-                                    if s.len() > 1 { panic!("only a character can be pushed: {}", s); }
-                                    self.output.push(Opcode::encode(opcode, s.chars().next().unwrap() as u16 as i16, operand2))
-                                },
-
-                                _ => panic!("Opcode cannot take string as an input: {:?}", opcode)
-                            }
-                        },
-                        TokenType::Num(operand1) => self.output.push(Opcode::encode(opcode, operand1, operand2))
-                    };
+                let token = self.advance().unwrap();
+                given += 1;
+                match self.operand(*kind, &token) {
+                    Ok(Some(OperandValue::Operand1(n))) => operand1 = n,
+                    Ok(Some(OperandValue::Operand2(n))) if opcode == Opcode::CALL && !(0..=3).contains(&n) => {
+                        diagnostics.push(self.error(&token, format!("CALL's mode out of range (0..=3): {}", n)));
+                        valid = false;
+                    },
+                    Ok(Some(OperandValue::Operand2(n))) => operand2 = n,
+                    Ok(Some(OperandValue::Arguments(n))) => arguments = n,
+                    Ok(Some(OperandValue::Immediate(n))) => immediate = Some(n),
+                    Ok(Some(OperandValue::Float(n))) => float = Some(n),
+                    Ok(None) => fixup = Some((*kind, token)),
+                    Err(diagnostic) => {
+                        diagnostics.push(diagnostic);
+                        valid = false;
+                    }
+                }
+            }
+
+            if let Some(TokenType::Num(_)) | Some(TokenType::Float(_)) = self.peek().map(|t| &t.token_type) {
+                let count = opcode.operands().len();
+                diagnostics.push(self.error(&self.source[self.index], format!("{} takes at most {} operand(s)", val, count)));
+                self.skip_operands();
+                valid = false;
+            }
 
+            if !valid {
+                continue;
+            }
+
+            match opcode {
+                Opcode::ENTER => {
+                    self.finish_enter(enter.take());
+                    if given == 0 {
+                        enter = Some((self.output.len(), 0));
+                    }
+                },
+                Opcode::LOAD | Opcode::STORE => {
+                    if let Some((_, slots)) = &mut enter {
+                        *slots = (*slots).max(operand1.saturating_add(1));
+                    }
                 },
+                _ => (),
+            }
+
+            if let Some((kind, token)) = fixup {
+                fixups.push((self.output.len(), kind, token));
+            }
+            if opcode == Opcode::CALL {
+                operand2 = Opcode::pack_call(operand2, arguments);
+            }
+
+            match (opcode, immediate, float) {
+                (_, _, Some(value)) => self.output.extend(Opcode::encode_push_wide(value.to_bits() as i64)),
+                (Opcode::PUSH, Some(value), _) => self.output.extend(Opcode::encode_push(value)),
+                (Opcode::PUSHW, Some(value), _) => self.output.extend(Opcode::encode_push_wide(value)),
+                _ => self.output.push(Opcode::encode(opcode, operand1, operand2)),
+            }
+        }
 
-                TokenType::Num(_) => panic!("Number encountered outside of being an operand or as an extra operand: {:?}", current_token)
+        self.finish_enter(enter);
+
+        for (index, kind, token) in fixups {
+            let name = match &token.token_type {
+                TokenType::Str(s) => s,
+                _ => unreachable!(),
+            };
+
+            let (opcode, _, operand2) = Opcode::decode(self.output[index]);
+            let value = match kind {
+                OperandKind::Global => self.globals.get(name).copied(),
+                // Targets are encoded in operand1, so a label past 32767 can't be jumped to.
+                _ => match self.labels.get(name) {
+                    Some(&address) if opcode != Opcode::JLE => match i16::try_from(address) {
+                        Ok(address) => Some(address),
+                        Err(_) => {
+                            diagnostics.push(self.error(&token, format!("Address out of range (0..={}): {} is at {}", i16::MAX, name, address)));
+                            continue;
+                        }
+                    },
+                    // `JLE` has no absolute mode, so its labels are written as offsets from the branch.
+                    Some(&address) => {
+                        let offset = match opcode.mode(operand2) {
+                            2 => index as i64 - address as i64,
+                            _ => address as i64 - index as i64,
+                        };
+                        match i16::try_from(offset) {
+                            Ok(offset) => Some(offset),
+                            Err(_) => {
+                                diagnostics.push(self.error(&token, format!("Offset out of range ({}..={}): {} is {} away", i16::MIN, i16::MAX, name, offset)));
+                                continue;
+                            }
+                        }
+                    },
+                    None => None,
+                },
+            };
+            match value {
+                Some(value) => self.output[index] = Opcode::encode(opcode, value, operand2),
+                None if kind == OperandKind::Global => diagnostics.push(self.error(&token, format!("Global doesn't exist: {}", name))),
+                None => diagnostics.push(self.error(&token, format!("Identifier doesn't exist: {}", name))),
             }
+        }
+
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            diagnostics.sort_by_key(|d| (d.line, d.column));
+            Err(diagnostics)
+        }
+    }
+
+    // Whether `token` can be an operand of the given kind. A name is taken as an operand unless it's
+    // optional, in which case it's the next opcode.
+    fn is_operand(token: Option<&Token>, kind: OperandKind) -> bool {
+        match token.map(|t| &t.token_type) {
+            Some(TokenType::Num(_)) | Some(TokenType::Float(_)) => true,
+            Some(TokenType::Str(_)) => !kind.is_optional(),
+            _ => false,
+        }
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.source.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.source.get(self.index)
+    }
+
+    // Converts an operand token into the field it's encoded in, or `None` for a label that hasn't
+    // been resolved yet.
+    fn operand(&self, kind: OperandKind, token: &Token) -> Result<Option<OperandValue>, Diagnostic> {
+        match (&token.token_type, kind) {
+            (TokenType::Num(n), OperandKind::Mode) => match i8::try_from(*n) {
+                Ok(n) => Ok(Some(OperandValue::Operand2(n))),
+                Err(_) => Err(self.error(token, format!("2nd operand out of range ({}..={}): {}", i8::MIN, i8::MAX, n))),
+            },
+            (TokenType::Num(n), OperandKind::Immediate) => Ok(Some(OperandValue::Immediate(*n))),
+            (TokenType::Num(n), OperandKind::Arguments) => match u8::try_from(*n) {
+                Ok(n) if n <= 63 => Ok(Some(OperandValue::Arguments(n))),
+                _ => Err(self.error(token, format!("Argument count out of range (0..=63): {}", n))),
+            },
+            (TokenType::Num(n), OperandKind::Count | OperandKind::Results | OperandKind::Slots) => match i16::try_from(*n) {
+                Ok(n) if n >= 0 => Ok(Some(OperandValue::Operand1(n))),
+                _ => Err(self.error(token, format!("1st operand out of range (0..={}): {}", i16::MAX, n))),
+            },
+            (TokenType::Num(n), _) => match i16::try_from(*n) {
+                Ok(n) => Ok(Some(OperandValue::Operand1(n))),
+                Err(_) => Err(self.error(token, format!("1st operand out of range ({}..={}): {}", i16::MIN, i16::MAX, n))),
+            },
+
+            // Floats are pushed as the bits of an `f64`, which always need the wide form.
+            (TokenType::Float(n), OperandKind::Immediate) => Ok(Some(OperandValue::Float(*n))),
+            (TokenType::Float(n), _) => Err(self.error(token, format!("{:?} operand cannot be a float: {}", kind, n))),
+
+            (TokenType::Str(_), OperandKind::Target) | (TokenType::Str(_), OperandKind::Global) => Ok(None),
+            (TokenType::Str(s), OperandKind::Immediate) => {
+                if s.chars().count() > 1 {
+                    return Err(self.error(token, format!("only a character can be pushed: {}", s)));
+                }
+                Ok(Some(OperandValue::Immediate(s.chars().next().unwrap() as i64)))
+            },
+            (TokenType::Str(s), _) => Err(self.error(token, format!("{:?} operand cannot be a string: {}", kind, s))),
+
+            (TokenType::Identifier(_), _) | (TokenType::Directive(_), _) => unreachable!(),
+        }
+    }
 
-            if self.source.len() == 0 { break }
+    fn finish_enter(&mut self, enter: Option<(usize, i16)>) {
+        if let Some((index, slots)) = enter {
+            self.output[index] = Opcode::encode(Opcode::ENTER, slots, 0);
         }
     }
 
+    // Handles a directive, which declares something rather than emitting an instruction.
+    fn directive(&mut self, name: &str, token: &Token) -> Result<(), Diagnostic> {
+        match name {
+            "global" => {
+                let global = match self.peek().map(|t| &t.token_type) {
+                    Some(TokenType::Str(s)) => s.clone(),
+                    _ => return Err(self.error(token, "Expected a name after .global")),
+                };
+                let name_token = self.advance().unwrap();
+
+                if self.globals.contains_key(&global) {
+                    return Err(self.error(&name_token, format!("Global defined more than once: {}", global)));
+                }
+                self.globals.insert(global, self.globals.len() as i16);
+                Ok(())
+            },
+            _ => Err(self.error(token, format!("Unknown directive: .{}", name))),
+        }
+    }
+
+    fn skip_operands(&mut self) {
+        while let Some(TokenType::Num(_)) | Some(TokenType::Float(_)) = self.peek().map(|t| &t.token_type) {
+            self.index += 1;
+        }
+    }
+
+    fn error<S: Into<String>>(&self, token: &Token, message: S) -> Diagnostic {
+        Diagnostic::at_token(self.source_file.clone(), token, message)
+    }
+
     pub fn write(&self) -> Result<(), std::io::Error> {
-        let mut buffer =  File::create(self.file_path.clone()).unwrap();
+        let mut buffer = File::create(self.file_path.clone())?;
         let mut file_data: Vec<u8> = Vec::new();
 
         self.output.iter().for_each(|x| {
@@ -92,4 +313,25 @@ impl Assembler {
 
         buffer.write_all(&file_data)
     }
-}
\ No newline at end of file
+
+    /// Writes the label table next to the binary, with a `.sym` extension, as `name address` lines.
+    pub fn write_symbols(&self) -> Result<(), std::io::Error> {
+        let mut labels: Vec<(&String, &usize)> = self.labels.iter().collect();
+        labels.sort_by_key(|(name, address)| (**address, name.as_str()));
+
+        let mut buffer = File::create(Path::new(&self.file_path).with_extension("sym"))?;
+        for (name, address) in labels {
+            writeln!(buffer, "{} {}", name, address)?;
+        }
+        Ok(())
+    }
+}
+
+enum OperandValue {
+    Operand1(i16),
+    // A full width value for `PUSH`, which gets widened to `PUSHW` if it needs to be.
+    Immediate(i64),
+    Float(f64),
+    Operand2(i8),
+    Arguments(u8),
+}
@@ -0,0 +1,54 @@
+use crate::parser::tokens::Token;
+use std::fmt;
+
+/// An error in a `.nar` file, pointing at the characters that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<F: Into<String>, M: Into<String>>(file: F, line: usize, column: usize, len: usize, message: M) -> Diagnostic {
+        Diagnostic { file: file.into(), line, column, len, message: message.into() }
+    }
+
+    pub fn at_token<F: Into<String>, M: Into<String>>(file: F, token: &Token, message: M) -> Diagnostic {
+        Diagnostic::new(file, token.line, token.column, token.len, message)
+    }
+
+    /// Formats the diagnostic like rustc does, quoting the offending line of `source` with a caret
+    /// under the span.
+    pub fn render(&self, source: &str) -> String {
+        let location = format!("{}:{}:{}", self.file, self.line, self.column);
+        let text = match source.lines().nth(self.line.wrapping_sub(1)) {
+            Some(text) => text,
+            None => return format!("error: {}\n --> {}\n", self.message, location),
+        };
+
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Keep tabs so the caret lines up with the quoted text.
+        let padding: String = text.chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!("error: {}\n{}--> {}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.message,
+            gutter, location,
+            gutter,
+            self.line, text,
+            gutter, padding, "^".repeat(self.len.max(1)))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
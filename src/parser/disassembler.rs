@@ -0,0 +1,132 @@
+use crate::vm::instruction::{Opcode, OperandKind};
+use std::collections::{BTreeMap, HashMap};
+
+/// Turns a program back into `.nar` source. Every jump and call target gets a label, named after
+/// the program's symbols where they're known and `label_<address>` otherwise. Absolute targets are
+/// written as labels; relative ones keep their offsets so the program reassembles to the same binary.
+pub struct Disassembler<'a> {
+    program: &'a [u32],
+    labels: BTreeMap<usize, Vec<String>>,
+    addresses: Vec<usize>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(program: &'a [u32]) -> Disassembler<'a> {
+        Disassembler::with_symbols(program, &HashMap::new())
+    }
+
+    pub fn with_symbols(program: &'a [u32], symbols: &HashMap<String, usize>) -> Disassembler<'a> {
+        let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (name, address) in symbols {
+            labels.entry(*address).or_default().push(name.clone());
+        }
+        labels.values_mut().for_each(|names| names.sort());
+
+        let mut addresses = Vec::new();
+        let mut address = 0;
+        while address < program.len() {
+            addresses.push(address);
+            if let Some(target) = Disassembler::target(address, program[address]) {
+                if target <= program.len() {
+                    labels.entry(target).or_insert_with(|| vec![format!("label_{}", target)]);
+                }
+            }
+            address += Opcode::decode(program[address]).0.width();
+        }
+
+        // A label can only be written in front of an instruction or at the end, so a target in the
+        // middle of a `PUSHW`, or a symbol past the end, is written as a number instead.
+        labels.retain(|address, _| *address == program.len() || addresses.binary_search(address).is_ok());
+
+        Disassembler { program, labels, addresses }
+    }
+
+    /// The names given to `address`, in the order they're written out.
+    pub fn labels_at(&self, address: usize) -> &[String] {
+        match self.labels.get(&address) {
+            Some(names) => names,
+            None => &[],
+        }
+    }
+
+    /// The address of each instruction, skipping over the words holding `PUSHW` values.
+    pub fn addresses(&self) -> &[usize] {
+        &self.addresses
+    }
+
+    /// Renders the whole program as `.nar` source that can be fed back into the `Lexer`.
+    pub fn disassemble(&self) -> String {
+        self.render(false)
+    }
+
+    /// Like `disassemble`, but with each instruction's address in front of it.
+    pub fn listing(&self) -> String {
+        self.render(true)
+    }
+
+    /// Renders a single instruction, e.g. `JNE     loop`, leaving out operands that are 0 and optional.
+    pub fn render_instruction(&self, address: usize) -> String {
+        let (opcode, operand1, operand2) = Opcode::decode(self.program[address]);
+        let operands = opcode.operands();
+
+        let mut rendered: Vec<String> = operands.iter().map(|kind| match kind {
+            OperandKind::Target => match Disassembler::target(address, self.program[address]) {
+                Some(target) if opcode.is_absolute(operand2) && self.labels.contains_key(&target) => self.labels[&target][0].clone(),
+                _ => operand1.to_string(),
+            },
+            OperandKind::Mode => opcode.mode(operand2).to_string(),
+            OperandKind::Arguments => Opcode::unpack_call(operand2).1.to_string(),
+            OperandKind::Immediate if opcode == Opcode::PUSHW => match self.program.get(address + 1..address + 3) {
+                Some(&[high, low]) => (((high as u64) << 32 | low as u64) as i64).to_string(),
+                _ => "0".into(),
+            },
+            OperandKind::Immediate | OperandKind::Variable | OperandKind::Count | OperandKind::Global
+            | OperandKind::Results | OperandKind::Slots | OperandKind::Padding => operand1.to_string(),
+        }).collect();
+
+        // `ENTER`'s count is always written out, since leaving it out asks the assembler to work it out.
+        while let Some(kind) = operands.get(rendered.len().wrapping_sub(1)) {
+            if !kind.is_optional() || *kind == OperandKind::Slots || rendered.last().unwrap() != "0" {
+                break;
+            }
+            rendered.pop();
+        }
+
+        match rendered.is_empty() {
+            true => format!("{:?}", opcode),
+            false => format!("{:<7} {}", format!("{:?}", opcode), rendered.join(" ")),
+        }
+    }
+
+    fn render(&self, addresses: bool) -> String {
+        let mut output = String::new();
+
+        for address in self.addresses.iter().copied().chain(std::iter::once(self.program.len())) {
+            for name in self.labels_at(address) {
+                if address > 0 {
+                    output.push('\n');
+                }
+                output.push_str(&format!("{}:\n", name));
+            }
+
+            if address < self.program.len() {
+                if addresses {
+                    output.push_str(&format!("{:>5}  ", address));
+                }
+                output.push_str(&format!("    {}\n", self.render_instruction(address)));
+            }
+        }
+
+        output
+    }
+
+    fn target(address: usize, instruction: u32) -> Option<usize> {
+        let (opcode, operand1, operand2) = Opcode::decode(instruction);
+        if !opcode.is_branch() {
+            return None;
+        }
+
+        let target = opcode.target(address, operand1, operand2);
+        if target < 0 { None } else { Some(target as usize) }
+    }
+}
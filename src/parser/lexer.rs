@@ -1,38 +1,49 @@
 use crate::parser::tokens::*;
+use crate::parser::diagnostic::*;
 
 pub struct Lexer {
     source: Vec<char>,
     index: usize,
     line: usize,
+    line_start: usize,
+    file: String,
 
     pub tokens: Vec<Token>
 }
 
 impl Lexer {
     pub fn new<S: Into<String>>(source: S) -> Lexer {
-        Lexer { source: source.into().chars().collect(), index: 0, tokens: Vec::new(), line: 1 }
+        Lexer::with_file(source, "<input>")
+    }
+
+    /// Like `new`, but diagnostics name `file` as the source of the errors.
+    pub fn with_file<S: Into<String>, F: Into<String>>(source: S, file: F) -> Lexer {
+        Lexer { source: source.into().chars().collect(), index: 0, tokens: Vec::new(), line: 1, line_start: 0, file: file.into() }
     }
 
     pub fn advance(&mut self) -> Option<char> {
         self.index += 1;
         if self.index <= self.source.len() {
-            return Some(self.source[self.index - 1])
+            Some(self.source[self.index - 1])
         }
         else {
-            return None
+            None
         }
     }
 
     pub fn peek(&self) -> Option<char> {
-        if self.index + 1 <= self.source.len() {
-            return Some(self.source[self.index])
+        if self.index < self.source.len() {
+            Some(self.source[self.index])
         }
         else {
-            return None
+            None
         }
     }
 
-    pub fn lex(&mut self) {
+    /// Tokenises the whole source, carrying on past errors so they can all be reported at once.
+    pub fn lex(&mut self) -> Result<(), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+
         loop {
             let current_index = self.index;
             let char_type = Lexer::get_char_type(match self.advance(){
@@ -42,29 +53,48 @@ impl Lexer {
 
             match char_type {
                 CharType::Comma | CharType::WhiteSpace | CharType::Return | CharType::Tab => continue,
-                CharType::Colon => panic!("Colon encountered outside of identifier, Line: {}", self.line),
-                CharType::Illegal => panic!("Illegal character ({}) encountered at line: {}", self.source[current_index], self.line),
+                CharType::Colon => diagnostics.push(self.error(current_index, 1, "Colon encountered outside of identifier")),
+                CharType::Illegal => {
+                    let message = format!("Illegal character ({}) encountered", self.source[current_index]);
+                    diagnostics.push(self.error(current_index, 1, message));
+                },
+
+                CharType::Newline => self.new_line(),
 
-                CharType::Newline => self.line += 1,
+                CharType::Semicolon => self.skip_line(),
+                CharType::Slash => {
+                    match self.peek() {
+                        Some('/') => self.skip_line(),
+                        Some('*') => {
+                            let (line, line_start) = (self.line, self.line_start);
+                            self.advance();
+
+                            if !self.skip_block_comment() {
+                                let column = current_index - line_start + 1;
+                                diagnostics.push(Diagnostic::new(self.file.clone(), line, column, 2, "Unterminated block comment"));
+                            }
+                        },
+                        _ => diagnostics.push(self.error(current_index, 1, "Illegal character (/) encountered")),
+                    }
+                },
 
                 CharType::Letter => {
                     loop {
                         let end_index = self.index;
-                        let c = Lexer::get_char_type(match self.peek(){
-                            Some(c) => c,
-                            None => {
-                                self.tokens.push(Token::new( TokenType::Str(self.source[current_index..end_index].iter().collect()), self.line ));
-                                break;
-                            }
-                        });
+                        let c = match self.peek() {
+                            Some(c) => Lexer::get_char_type(c),
+                            None => CharType::Newline,
+                        };
 
                         if CharType::Colon == c {
                             self.advance();
-                            self.tokens.push(Token::new( TokenType::Identifier(self.source[current_index..end_index].iter().collect()), self.line ));
+                            let token_type = TokenType::Identifier(self.source[current_index..end_index].iter().collect());
+                            self.push_token(token_type, current_index, end_index);
                             break;
                         }
                         else if c != CharType::Letter && c != CharType::Num {
-                            self.tokens.push(Token::new( TokenType::Str(self.source[current_index..end_index].iter().collect()), self.line ));
+                            let token_type = TokenType::Str(self.source[current_index..end_index].iter().collect());
+                            self.push_token(token_type, current_index, end_index);
                             break;
                         }
 
@@ -73,28 +103,208 @@ impl Lexer {
                 },
 
                 CharType::Num => {
-                    loop {
-                        let end_index = self.index;
-                        let c = Lexer::get_char_type(match self.peek(){
-                            Some(c) => c,
-                            None => {
-                                let num = self.source[current_index..end_index].iter().collect::<String>().parse::<i16>().unwrap();
-                                self.tokens.push(Token::new(TokenType::Num(num), self.line));
-                                break;
+                    if let Err(diagnostic) = self.lex_number(current_index) {
+                        diagnostics.push(diagnostic);
+                    }
+                },
+                CharType::Minus => {
+                    match self.peek().map(Lexer::get_char_type) {
+                        Some(CharType::Num) => {
+                            if let Err(diagnostic) = self.lex_number(current_index) {
+                                diagnostics.push(diagnostic);
                             }
-                        });
-
-                        if c != CharType::Num {
-                            let num = self.source[current_index..end_index].iter().collect::<String>().parse::<i16>().unwrap();
-                            self.tokens.push(Token::new(TokenType::Num(num), self.line));
-                            break;
-                        }
-
-                        self.advance();
+                        },
+                        _ => diagnostics.push(self.error(current_index, 1, "Illegal character (-) encountered")),
+                    }
+                },
+                CharType::Quote => {
+                    if let Err(diagnostic) = self.lex_char(current_index) {
+                        diagnostics.push(diagnostic);
+                    }
+                },
+                CharType::Dot => {
+                    match self.peek().map(Lexer::get_char_type) {
+                        Some(CharType::Letter) => {
+                            while let Some(CharType::Letter) | Some(CharType::Num) = self.peek().map(Lexer::get_char_type) {
+                                self.advance();
+                            }
+                            let token_type = TokenType::Directive(self.source[current_index + 1..self.index].iter().collect());
+                            self.push_token(token_type, current_index, self.index);
+                        },
+                        _ => diagnostics.push(self.error(current_index, 1, "Illegal character (.) encountered")),
                     }
                 }
             }
         }
+
+        if diagnostics.is_empty() { Ok(()) } else { Err(diagnostics) }
+    }
+
+    // Reads a decimal, `0x` hexadecimal or `0b` binary literal whose first character (or minus sign)
+    // starts at `start`. A decimal point makes it a float, such as `-1.5` or `2.5e-3`, and has to be
+    // followed by a digit.
+    fn lex_number(&mut self, start: usize) -> Result<(), Diagnostic> {
+        while let Some(CharType::Num) | Some(CharType::Letter) = self.peek().map(Lexer::get_char_type) {
+            self.advance();
+        }
+
+        if self.peek() == Some('.') {
+            if self.source.get(self.index + 1).is_some_and(|c| c.is_ascii_digit()) {
+                return self.lex_float(start);
+            }
+
+            // Without a digit after the point, e.g. `1.e`, it's a malformed float rather than a
+            // number followed by a directive.
+            self.advance();
+            while let Some(CharType::Num) | Some(CharType::Letter) = self.peek().map(Lexer::get_char_type) {
+                self.advance();
+            }
+            let text: String = self.source[start..self.index].iter().collect();
+            return Err(self.error(start, self.index - start, format!("Invalid number: {}", text)));
+        }
+
+        let end = self.index;
+        let text: String = self.source[start..end].iter().collect();
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.as_str()),
+        };
+
+        let (radix, digits) = if let Some(hex) = digits.strip_prefix("0x") {
+            (16, hex)
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            (2, binary)
+        } else {
+            (10, digits)
+        };
+
+        let valid = !digits.is_empty() && digits.chars().all(|c| c.is_digit(radix));
+        if !valid {
+            return Err(self.error(start, end - start, format!("Invalid number: {}", text)));
+        }
+
+        let parsed = match negative {
+            true => i64::from_str_radix(&format!("-{}", digits), radix),
+            false => i64::from_str_radix(digits, radix),
+        };
+        match parsed {
+            Ok(num) => {
+                self.push_token(TokenType::Num(num), start, end);
+                Ok(())
+            },
+            Err(_) => Err(self.error(start, end - start, format!("Number out of range: {}", text))),
+        }
+    }
+
+    // Reads the rest of a float literal, from the decimal point onwards.
+    fn lex_float(&mut self, start: usize) -> Result<(), Diagnostic> {
+        self.advance();
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '-' || c == '+') && matches!(self.source[self.index - 1], 'e' | 'E');
+            if !exponent_sign && !matches!(Lexer::get_char_type(c), CharType::Num | CharType::Letter) {
+                break;
+            }
+            self.advance();
+        }
+
+        let end = self.index;
+        let text: String = self.source[start..end].iter().collect();
+        match text.parse::<f64>() {
+            Ok(num) if num.is_finite() => {
+                self.push_token(TokenType::Float(num), start, end);
+                Ok(())
+            },
+            Ok(_) => Err(self.error(start, end - start, format!("Number out of range: {}", text))),
+            Err(_) => Err(self.error(start, end - start, format!("Invalid number: {}", text))),
+        }
+    }
+
+    // Reads a character literal such as `'a'` or `'\n'` whose opening quote is at `start`.
+    fn lex_char(&mut self, start: usize) -> Result<(), Diagnostic> {
+        let c = match self.advance_in_line() {
+            Some('\\') => match self.advance_in_line() {
+                Some('n') => Ok('\n'),
+                Some('t') => Ok('\t'),
+                Some('r') => Ok('\r'),
+                Some('0') => Ok('\0'),
+                Some('\\') => Ok('\\'),
+                Some('\'') => Ok('\''),
+                Some(c) => Err(format!("Unknown escape sequence: \\{}", c)),
+                None => Err("Unterminated character literal".into()),
+            },
+            Some(c) => Ok(c),
+            None => return Err(self.error(start, 1, "Unterminated character literal")),
+        };
+
+        // Skip to the closing quote so a malformed literal is only reported once.
+        let mut extra = false;
+        while let Some(next) = self.peek() {
+            if next == '\'' || next == '\n' || next == ' ' || next == '\t' {
+                break;
+            }
+            extra = true;
+            self.advance();
+        }
+
+        if self.peek() != Some('\'') {
+            return Err(self.error(start, self.index - start, "Unterminated character literal"));
+        }
+        self.advance();
+
+        match c {
+            Ok(_) if extra => Err(self.error(start, self.index - start, "Character literal must contain a single character")),
+            Ok(c) => {
+                self.push_token(TokenType::Num(c as i64), start, self.index);
+                Ok(())
+            },
+            Err(message) => Err(self.error(start, self.index - start, message)),
+        }
+    }
+
+    // Like `advance`, but stops at the end of the line, leaving the newline to end it.
+    fn advance_in_line(&mut self) -> Option<char> {
+        match self.peek() {
+            Some('\n') | None => None,
+            Some(_) => self.advance(),
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.index;
+    }
+
+    // Leaves the newline in place so it still ends the line.
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.advance();
+        }
+    }
+
+    // Skips past the closing `*/`, returning false if the source ends first.
+    fn skip_block_comment(&mut self) -> bool {
+        while let Some(c) = self.advance() {
+            if c == '\n' {
+                self.new_line();
+            }
+            else if c == '*' && self.peek() == Some('/') {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn push_token(&mut self, token_type: TokenType, start: usize, end: usize) {
+        let token = Token::with_span(token_type, self.line, start - self.line_start + 1, end - start);
+        self.tokens.push(token);
+    }
+
+    fn error<S: Into<String>>(&self, start: usize, len: usize, message: S) -> Diagnostic {
+        Diagnostic::new(self.file.clone(), self.line, start - self.line_start + 1, len, message)
     }
 
     pub fn get_char_type(input: char) -> CharType {
@@ -104,6 +314,11 @@ impl Lexer {
             ':' => CharType::Colon,
             ',' => CharType::Comma,
             ' ' => CharType::WhiteSpace,
+            ';' => CharType::Semicolon,
+            '/' => CharType::Slash,
+            '-' => CharType::Minus,
+            '\'' => CharType::Quote,
+            '.' => CharType::Dot,
 
             '\n' => CharType::Newline,
             '\r' => CharType::Return,
@@ -121,10 +336,15 @@ pub enum CharType {
     Colon,
     Comma,
     WhiteSpace,
+    Semicolon,
+    Slash,
+    Minus,
+    Quote,
+    Dot,
 
     Newline,
     Return,
     Tab,
 
     Illegal,
-}
\ No newline at end of file
+}
@@ -1,4 +1,6 @@
 pub mod assembler;
+pub mod diagnostic;
+pub mod disassembler;
 pub mod reader;
 pub mod lexer;
 pub mod tokens;
\ No newline at end of file
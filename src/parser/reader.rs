@@ -1,26 +1,81 @@
 use crate::vm::instruction::Opcode;
 
-use std::fs::read;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{read_to_string, File};
+use std::io::{self, Read};
+
+/// The instructions of a program, as the assembler outputs them and the CPU runs them.
+pub type Program = Vec<u32>;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// The program's length in bytes, which isn't a whole number of instructions.
+    TruncatedInstruction(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::TruncatedInstruction(n) => write!(f, "program is {} bytes long, which isn't a multiple of 4", n),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::TruncatedInstruction(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> LoadError {
+        LoadError::Io(error)
+    }
+}
 
 pub struct Reader;
 
 impl Reader {
-    pub fn read<S: Into<String>>(file_path: S) -> Vec<u32> {
+    /// Loads the program in the file at `file_path`.
+    pub fn read<S: Into<String>>(file_path: S) -> Result<Program, LoadError> {
+        Reader::from_reader(File::open(file_path.into())?)
+    }
 
-        let mut bytes = read(file_path.into()).unwrap();
-        let mut return_val: Vec<u32> = Vec::new();
+    /// Loads a program from anything readable, such as a socket or an in-memory buffer.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Program, LoadError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Reader::from_bytes(&bytes)
+    }
 
-        if bytes.len() % 4 != 0 { panic!("bytes len not a multiple of 4") }
+    /// Loads a program from its bytes, e.g. ones embedded with `include_bytes!`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, LoadError> {
+        if bytes.len() % 4 != 0 {
+            return Err(LoadError::TruncatedInstruction(bytes.len()));
+        }
 
-        loop {
-            return_val.push(Opcode::byte_array_to_instruction([
-                bytes.remove(0),
-                bytes.remove(0),
-                bytes.remove(0),
-                bytes.remove(0)
-            ]));
+        Ok(bytes.chunks_exact(4)
+            .map(|chunk| Opcode::byte_array_to_instruction([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect())
+    }
 
-            if bytes.len() == 0 { break return_val }
+    /// Reads a label table written by `Assembler::write_symbols`, skipping malformed lines.
+    pub fn read_symbols<S: Into<String>>(file_path: S) -> Result<HashMap<String, usize>, std::io::Error> {
+        let mut symbols = HashMap::new();
+        for line in read_to_string(file_path.into())?.lines() {
+            let mut parts = line.split_whitespace();
+            if let (Some(name), Some(address), None) = (parts.next(), parts.next(), parts.next()) {
+                if let Ok(address) = address.parse::<usize>() {
+                    symbols.insert(name.to_string(), address);
+                }
+            }
         }
+        Ok(symbols)
     }
 }
\ No newline at end of file
@@ -2,17 +2,27 @@
 pub enum TokenType {
     Identifier(String),
     Str(String),
-    Num(i16)
+    /// An assembler directive such as `.global`, without the dot.
+    Directive(String),
+    Num(i64),
+    Float(f64),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
-    pub line: usize
+    pub line: usize,
+    pub column: usize,
+    pub len: usize
 }
 
 impl Token {
     pub fn new(token_type: TokenType, line: usize) -> Token {
-        Token { token_type, line }
+        Token { token_type, line, column: 0, len: 0 }
     }
-}
\ No newline at end of file
+
+    /// A token covering `len` characters starting at `column` (counted from 1) of `line`.
+    pub fn with_span(token_type: TokenType, line: usize, column: usize, len: usize) -> Token {
+        Token { token_type, line, column, len }
+    }
+}
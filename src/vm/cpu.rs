@@ -1,338 +1,700 @@
 use crate::vm::instruction::*;
 use crate::vm::frame::*;
-use std::io::{stdin, stdout, Write};
+use crate::vm::error::*;
+use crate::vm::host::*;
+use crate::vm::limits::*;
+use crate::vm::predecode::*;
+use crate::vm::trace::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::time::Instant;
+
+/// The type of every value on the stack and in variables. Floats are stored as the bits of an
+/// `f64`, and it's up to the opcodes to treat a value as one or the other.
+pub type Value = i64;
+
+pub fn float_to_value(n: f64) -> Value {
+    n.to_bits() as Value
+}
+
+pub fn value_to_float(n: Value) -> f64 {
+    f64::from_bits(n as u64)
+}
+
+/// What `ADD`, `SUB` and `MUL` do when the result doesn't fit in a `Value`. Whichever is used,
+/// `overflow_flag` records whether the last arithmetic instruction overflowed. Only `ADD`, `SUB`,
+/// `MUL` and `CMP` can set it, and every other arithmetic instruction clears it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OverflowMode {
+    Wrapping,
+    Checked,
+    Saturating,
+}
+
+// How many instructions run between checks of the wall-clock deadline.
+const DEADLINE_INTERVAL: u64 = 1024;
 
-pub struct CPU {
+pub struct CPU<H: Host = StdHost> {
     program: Vec<u32>,
+    // `program` decoded ahead of time, one entry per word, which is what actually gets executed.
+    instrs: Vec<Instr>,
+    host: H,
     current_address: usize,
 
-    pub stack: Vec<i16>,
+    pub stack: Vec<Value>,
     pub call_stack: Vec<Frame>,
+    // Every frame's local slots, one frame after another.
+    locals: Vec<Value>,
+    memory: Vec<Value>,
+    globals: HashMap<i16, Value>,
 
     zero_flag: bool,
     sign_flag: bool,
+    overflow_flag: bool,
+    overflow_mode: OverflowMode,
+
+    limits: Limits,
+    executed: u64,
+
+    tracer: Option<Box<dyn TraceSink>>,
+    // Reused by `STDOUT` for its text.
+    output: String,
 }
 
 impl CPU {
     pub fn new(program: Vec<u32>) -> CPU {
+        CPU::with_host(program, StdHost)
+    }
+}
+
+impl<H: Host> CPU<H> {
+    pub fn with_host(program: Vec<u32>, host: H) -> CPU<H> {
         CPU {
+            instrs: predecode(&program),
             program,
+            host,
             current_address: 0,
             stack: Vec::new(),
-            call_stack: vec![Frame::new(usize::MAX)],
+            call_stack: vec![Frame::new(usize::MAX, 0, 0)],
+            locals: Vec::new(),
+            memory: Vec::new(),
+            globals: HashMap::new(),
             sign_flag: false,
             zero_flag: false,
+            overflow_flag: false,
+            overflow_mode: OverflowMode::Wrapping,
+            limits: Limits::unlimited(),
+            executed: 0,
+            tracer: None,
+            output: String::new(),
         }
     }
 
-    pub fn run(&mut self) -> Result<i16, String> {
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    pub fn into_host(self) -> H {
+        self.host
+    }
+
+    pub fn program(&self) -> &[u32] {
+        &self.program
+    }
+
+    /// The heap, which `MLOAD` and `MSTORE` address a word at a time.
+    pub fn memory(&self) -> &[Value] {
+        &self.memory
+    }
+
+    /// Lets the host fill in the heap before running, or resize it. `GROW` only checks the memory
+    /// limit against what the program asks for.
+    pub fn memory_mut(&mut self) -> &mut Vec<Value> {
+        &mut self.memory
+    }
+
+    /// The local slots of the frame `depth` calls deep, where 0 is the outermost frame.
+    pub fn locals(&self, depth: usize) -> Option<&[Value]> {
+        let start = self.call_stack.get(depth)?.locals_base;
+        let end = self.call_stack.get(depth + 1).map_or(self.locals.len(), |frame| frame.locals_base);
+        Some(&self.locals[start..end])
+    }
+
+    /// Variables shared by every frame, which `GLOAD` and `GSTORE` use.
+    pub fn globals(&self) -> &HashMap<i16, Value> {
+        &self.globals
+    }
+
+    pub fn current_address(&self) -> usize {
+        self.current_address
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        self.zero_flag
+    }
+
+    pub fn sign_flag(&self) -> bool {
+        self.sign_flag
+    }
+
+    pub fn overflow_flag(&self) -> bool {
+        self.overflow_flag
+    }
+
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow_mode
+    }
+
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow_mode = mode;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Sends a `TraceEvent` to `tracer` for every instruction executed from now on.
+    pub fn set_tracer(&mut self, tracer: Box<dyn TraceSink>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Box<dyn TraceSink>> {
+        self.tracer.take()
+    }
+
+    pub fn run(&mut self) -> Result<Value, Fault> {
         loop {
-            if let Some(e) = self.execute_instruction() {
-                if e == "jumped" {
-                    continue;
-                }
-                else if e == "halt" {
-                    if self.stack.len() > 0 {
-                        return Ok(self.stack.pop().unwrap())
-                    } else {
-                        return Ok(0)
-                    }
-                }
-                return Err(format!("{} at instruction {} ({:?})", e, self.current_address, Opcode::decode(self.program[self.current_address])));
+            if self.step()? == StepOutcome::Halted {
+                return Ok(self.stack.pop().unwrap_or(0));
             }
+        }
+    }
+
+    /// Executes the instruction at `current_address` and moves on to the next one.
+    /// A halted CPU stays on its `HALT` instruction.
+    pub fn step(&mut self) -> Result<StepOutcome, Fault> {
+        let address = self.current_address;
+        let stack_before = match self.tracer {
+            Some(_) => self.stack.clone(),
+            None => Vec::new(),
+        };
+
+        let outcome = match self.check_budget().and_then(|_| self.execute_instruction()) {
+            Ok(outcome) => outcome,
+            Err(error) => return Err(self.fault(error, address)),
+        };
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(&TraceEvent {
+                address,
+                instruction: Opcode::decode(self.program[address]),
+                stack_before,
+                stack_after: self.stack.clone(),
+                zero_flag: self.zero_flag,
+                sign_flag: self.sign_flag,
+                overflow_flag: self.overflow_flag,
+            });
+        }
+
+        self.executed += 1;
+        if let Err(error) = self.check_depth() {
+            return Err(self.fault(error, address));
+        }
+
+        if outcome == StepOutcome::Continue {
             self.current_address += 1;
         }
+        Ok(outcome)
     }
 
-    pub fn execute_instruction(&mut self) -> Option<String> {
-        let (opcode, operand1, operand2) = Opcode::decode(self.program[self.current_address]);
-        match opcode {
-            Opcode::ILG => return Some("Illegal character".into()),
-            Opcode::HALT => return Some("halt".into()),
-            Opcode::LEN => self.stack.push(self.stack.len() as i16),
+    pub fn execute_instruction(&mut self) -> Result<StepOutcome, VmError> {
+        let instr = match self.instrs.get(self.current_address) {
+            Some(i) => *i,
+            None => return Err(VmError::PcOutOfBounds(self.current_address as i64)),
+        };
 
-            Opcode::POP => {
-                match self.stack.pop() {
-                    Some(_) => (),
-                    None => return Some("no character to pop".into()),
-                };
+        match instr {
+            Instr::Illegal(byte) => return Err(VmError::IllegalOpcode(byte)),
+            Instr::Halt => return Ok(StepOutcome::Halted),
+            Instr::Len => self.stack.push(self.stack.len() as Value),
+
+            Instr::Pop => {
+                self.pop()?;
             },
-            Opcode::PUSH => self.stack.push(operand1),
-            Opcode::DUP => {
-                let temp = match self.stack.pop() {
-                    Some(val) => val,
-                    None => return Some("no character to pop".into())
-                };
+            Instr::Push(value) => self.stack.push(value),
+            Instr::PushWide(value) => {
+                self.stack.push(value);
 
-                self.stack.push(temp.clone());
-                self.stack.push(temp.clone());
+                // Step over the two words holding the value.
+                self.current_address += 2;
             },
+            Instr::PushTruncated => return Err(VmError::PcOutOfBounds(self.program.len() as i64)),
+            Instr::Dup => {
+                let temp = self.pop()?;
 
-            Opcode::ADD => {
-                let n1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
-                let n2 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
+                self.stack.push(temp);
+                self.stack.push(temp);
+            },
 
-                self.stack.push(n2 + n1);
+            Instr::Swap => {
+                let index = self.nth_from_top(1)?;
+                self.stack.swap(index, index + 1);
+            },
+            Instr::Over => {
+                let index = self.nth_from_top(1)?;
+                self.stack.push(self.stack[index]);
+            },
+            Instr::Rot => {
+                let index = self.nth_from_top(2)?;
+                let temp = self.stack.remove(index);
+                self.stack.push(temp);
+            },
+            Instr::Nip => {
+                let index = self.nth_from_top(1)?;
+                self.stack.remove(index);
+            },
+            Instr::Tuck => {
+                let index = self.nth_from_top(1)?;
+                let temp = self.stack[index + 1];
+                self.stack.insert(index, temp);
+            },
+            Instr::Pick(n) => {
+                let index = self.nth_from_top(n as usize)?;
+                self.stack.push(self.stack[index]);
+            },
+            Instr::Roll(n) => {
+                let index = self.nth_from_top(n as usize)?;
+                let temp = self.stack.remove(index);
+                self.stack.push(temp);
+            },
+            Instr::Drop(n) => {
+                let len = self.stack.len();
+                match len.checked_sub(n as usize) {
+                    Some(new_len) if n >= 0 => self.stack.truncate(new_len),
+                    _ => return Err(VmError::StackUnderflow),
+                }
+            },
+
+            Instr::Add => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
+
+                let result = self.overflowing(n2.overflowing_add(n1), n2.saturating_add(n1))?;
+                self.stack.push(result);
                 self.set_flags();
             },
-            Opcode::SUB => {
-                let n1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
-                let n2 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
+            Instr::Sub => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
 
-                self.stack.push(n2 - n1);
+                let result = self.overflowing(n2.overflowing_sub(n1), n2.saturating_sub(n1))?;
+                self.stack.push(result);
                 self.set_flags();
             },
 
-            Opcode::MUL => {
-                let n1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
-                let n2 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
+            Instr::Mul => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
 
-                self.stack.push(n2 * n1);
+                let result = self.overflowing(n2.overflowing_mul(n1), n2.saturating_mul(n1))?;
+                self.stack.push(result);
                 self.set_flags();
             },
-            Opcode::DIV => {
-                let n1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
-                let n2 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
+            Instr::Div => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
+                if n1 == 0 {
+                    return Err(VmError::DivisionByZero);
+                }
 
-                self.stack.push(n2 / n1);
+                // `Value::MIN / -1` has no sensible result to wrap or saturate to, whatever the mode.
+                match n2.checked_div(n1) {
+                    Some(result) => self.stack.push(result),
+                    None => return Err(VmError::Overflow),
+                }
+                self.overflow_flag = false;
                 self.set_flags();
             },
-            Opcode::MOD => {
-                let n1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
-                let n2 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
+            Instr::Mod => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
+                if n1 == 0 {
+                    return Err(VmError::DivisionByZero);
+                }
 
-                self.stack.push(n2 % n1);
+                // `Value::MIN % -1` is 0, but the plain `%` operator still panics on it.
+                self.stack.push(n2.wrapping_rem(n1));
+                self.overflow_flag = false;
                 self.set_flags();
             },
 
-            Opcode::CMP => {
-                let n1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
-                let n2 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
-                };
+            Instr::And | Instr::Or | Instr::Xor => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
 
-                self.stack.push(n2 - n1);
+                self.stack.push(match instr {
+                    Instr::And => n2 & n1,
+                    Instr::Or => n2 | n1,
+                    _ => n2 ^ n1,
+                });
+                self.overflow_flag = false;
                 self.set_flags();
             },
-            Opcode::JMP => {
-                if operand2 == 0 {
-                    self.current_address = operand1 as usize;
-                } else {
-                    self.current_address += operand1 as usize;
-                }
+            Instr::Not => {
+                let num1 = self.pop()?;
 
-                return Some("jumped".into());
-            },
-
-            Opcode::JE => {
-                if self.zero_flag {
-                    if operand2 == 2 {
-                        self.current_address -= operand1 as usize;
-                    }
-                    else if operand2 == 1 {
-                        self.current_address += operand1 as usize;
-                    }
-                    else {
-                        self.current_address = operand1 as usize;
-                    }
-                    return Some("jumped".into());
-                }
+                self.stack.push(!num1);
+                self.overflow_flag = false;
+                self.set_flags();
             },
-            Opcode::JNE => {
-                if !self.zero_flag {
-                    if operand2 == 2 {
-                        self.current_address -= operand1 as usize;
-                    }
-                    else if operand2 == 1 {
-                        self.current_address += operand1 as usize;
-                    }
-                    else {
-                        self.current_address = operand1 as usize;
-                    }
-                    return Some("jumped".into());
-                }
+            Instr::Shl | Instr::Shr | Instr::Sar => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
+
+                // Shifting by a negative amount, or by 64 or more, shifts every bit out.
+                let amount = u32::try_from(n1).unwrap_or(u32::MAX);
+                self.stack.push(match instr {
+                    Instr::Shl => n2.checked_shl(amount).unwrap_or(0),
+                    Instr::Shr => (n2 as u64).checked_shr(amount).unwrap_or(0) as Value,
+                    _ => n2.checked_shr(amount).unwrap_or(if n2 < 0 { -1 } else { 0 }),
+                });
+                self.overflow_flag = false;
+                self.set_flags();
             },
 
-            Opcode::JG => {
-                if self.sign_flag {
-                    if operand2 == 2 {
-                        self.current_address -= operand1 as usize;
-                    }
-                    else if operand2 == 1 {
-                        self.current_address += operand1 as usize;
-                    }
-                    else {
-                        self.current_address = operand1 as usize;
-                    }
-                    return Some("jumped".into());
-                }
+            Instr::MLoad => {
+                let address = self.pop()?;
+                let index = self.memory_index(address)?;
+                self.stack.push(self.memory[index]);
             },
-            Opcode::JL => {
-                if !self.sign_flag {
-                    if operand2 == 2 {
-                        self.current_address -= operand1 as usize;
-                    }
-                    else if operand2 == 1 {
-                        self.current_address += operand1 as usize;
-                    }
-                    else {
-                        self.current_address = operand1 as usize;
-                    }
-                    return Some("jumped".into());
+            Instr::MStore => {
+                let value = self.pop()?;
+                let address = self.pop()?;
+
+                let index = self.memory_index(address)?;
+                self.memory[index] = value;
+            },
+            Instr::MemSize => self.stack.push(self.memory.len() as Value),
+            Instr::Grow => {
+                // Adds `words` zeroed words to the end of the heap, leaving the old size on the stack.
+                let words = self.pop()?;
+                let old_size = self.memory.len();
+                let new_size = match usize::try_from(words).ok().and_then(|w| old_size.checked_add(w)) {
+                    Some(n) => n,
+                    None => return Err(VmError::MemoryOutOfBounds(words)),
+                };
+
+                // An allocator that can't find the memory counts as hitting the limit too.
+                let max = self.limits.max_memory.unwrap_or(DEFAULT_MAX_MEMORY);
+                if new_size > max || self.memory.try_reserve_exact(new_size - old_size).is_err() {
+                    return Err(VmError::MemoryLimitExceeded(max));
                 }
+
+                self.memory.resize(new_size, 0);
+                self.stack.push(old_size as Value);
             },
 
-            Opcode::JGE => {
-                if self.sign_flag || self.zero_flag {
-                    if operand2 == 2 {
-                        self.current_address -= operand1 as usize;
-                    }
-                    else if operand2 == 1 {
-                        self.current_address += operand1 as usize;
-                    }
-                    else {
-                        self.current_address = operand1 as usize;
-                    }
-                    return Some("jumped".into());
+            Instr::Cmp => {
+                let n1 = self.pop()?;
+                let n2 = self.pop()?;
+                let (difference, overflowed) = n2.overflowing_sub(n1);
+
+                // The flags come from comparing the values, so they're right even if the difference wrapped.
+                self.stack.push(difference);
+                self.overflow_flag = overflowed;
+                self.zero_flag = n2 == n1;
+                if n2 != n1 {
+                    self.sign_flag = n2 > n1;
                 }
             },
-            Opcode::JLE => {
-                if !self.sign_flag || self.zero_flag {
-                    if operand2 == 2 {
-                        self.current_address -= operand1 as usize;
-                    }
-                    else if operand2 == 1 {
-                        self.current_address += operand1 as usize;
-                    }
-                    else {
-                        self.current_address += operand1 as usize;
-                    }
-                   
-                    return Some("jumped".into());
+
+            Instr::FAdd | Instr::FSub | Instr::FMul | Instr::FDiv => {
+                let n1 = value_to_float(self.pop()?);
+                let n2 = value_to_float(self.pop()?);
+
+                let result = match instr {
+                    Instr::FAdd => n2 + n1,
+                    Instr::FSub => n2 - n1,
+                    Instr::FMul => n2 * n1,
+                    _ => n2 / n1,
+                };
+                self.stack.push(float_to_value(result));
+                self.overflow_flag = false;
+                self.set_float_flags(result);
+            },
+            Instr::FCmp => {
+                let n1 = value_to_float(self.pop()?);
+                let n2 = value_to_float(self.pop()?);
+
+                // NaN isn't equal to, or greater than, anything.
+                self.stack.push(float_to_value(n2 - n1));
+                self.overflow_flag = false;
+                self.zero_flag = n2 == n1;
+                if n2 != n1 {
+                    self.sign_flag = n2 > n1;
                 }
             },
+            Instr::IToF => {
+                let num1 = self.pop()?;
+                self.stack.push(float_to_value(num1 as f64));
+                self.overflow_flag = false;
+                self.set_float_flags(num1 as f64);
+            },
+            Instr::FToI => {
+                // Rounds towards zero, saturating at the ends of the range, and NaN becomes 0.
+                let num1 = value_to_float(self.pop()?);
+                self.stack.push(num1 as Value);
+                self.overflow_flag = false;
+                self.set_flags();
+            },
 
-            Opcode::STDIN => {
-                let mut c = String::new();
-                stdin().read_line(&mut c);
-                self.stack.push(match c.trim().parse::<i16>() {
+            Instr::Jmp(target) => return self.jump(target),
+            Instr::Je(target) if self.zero_flag => return self.jump(target),
+            Instr::Jne(target) if !self.zero_flag => return self.jump(target),
+            Instr::Jg(target) if self.sign_flag => return self.jump(target),
+            Instr::Jl(target) if !self.sign_flag => return self.jump(target),
+            Instr::Jge(target) if self.sign_flag || self.zero_flag => return self.jump(target),
+            Instr::Jle(target) if !self.sign_flag || self.zero_flag => return self.jump(target),
+            Instr::Je(_) | Instr::Jne(_) | Instr::Jg(_) | Instr::Jl(_) | Instr::Jge(_) | Instr::Jle(_) => (),
+
+            Instr::Stdin => {
+                let c = match self.host.read_line() {
+                    Some(line) => line,
+                    None => return Err(VmError::EndOfInput),
+                };
+                self.stack.push(match c.trim().parse::<Value>() {
                     Ok(val) => val,
-                    Err(e) => return Some(format!("Couldn't parse string, Err: {}", e)),
+                    Err(_) => return Err(VmError::InputParse(c.trim().into())),
                 });
             },
 
-            Opcode::STDOUT => {
-                let num1 = match self.stack.pop() {
-                    Some(n) => n,
-                    None => return Some("no character to pop".into()),
+            Instr::Stdout(format) => {
+                let num1 = self.pop()?;
+
+                // Formatted into a buffer that's kept between instructions, so printing doesn't allocate.
+                self.output.clear();
+                let _ = match format {
+                    3 => write!(self.output, "{}", num1 as u8 as char),
+                    2 => write!(self.output, "{}", num1 as u8),
+                    1 => writeln!(self.output, "{}", num1 as u8 as char),
+                    4 => writeln!(self.output, "{:?}", value_to_float(num1)),
+                    5 => write!(self.output, "{:?}", value_to_float(num1)),
+                    _ => writeln!(self.output, "{}", num1),
                 };
+                self.host.write(&self.output);
+
+                self.stack.push(num1);
+            },
 
-                match operand2 {
-                    3 => {
-                        print!("{}", num1 as u8 as char);
-                        let _ = stdout().flush();
-                    }
-                    2 => {
-                        print!("{}", num1 as u8);
-                        let _ = stdout().flush();
-                    }
-                    1 => println!("{}", num1 as u8 as char),
-                    _ => println!("{}", num1),
+            Instr::Load(n) => {
+                // A slot that's never been stored to reads as 0.
+                let index = self.local_index(n)?;
+                self.stack.push(self.locals.get(index).copied().unwrap_or(0));
+            },
+
+            Instr::Store(n) => {
+                let num1 = self.pop()?;
+
+                // The current frame's slots are the last ones, so it can grow to fit any variable,
+                // whether or not an `ENTER` reserved it.
+                let index = self.local_index(n)?;
+                if index >= self.locals.len() {
+                    self.locals.resize(index + 1, 0);
                 }
+                self.locals[index] = num1;
+            },
 
-                self.stack.push(num1);
+            Instr::Enter(n) => {
+                // Only ever adds slots, so the arguments `CALL` put in the first ones are kept.
+                let slots = self.call_stack.last().unwrap().locals_base + n.max(0) as usize;
+                if slots > self.locals.len() {
+                    self.locals.resize(slots, 0);
+                }
             },
 
-            Opcode::LOAD => {
-                self.stack.push(match self.call_stack.last().unwrap().load(&operand1) {
-                    Some(n) => *n,
-                    None => return Some(format!("{} is not a variable", operand1))
+            Instr::GLoad(n) => {
+                self.stack.push(match self.globals.get(&n) {
+                    Some(value) => *value,
+                    None => return Err(VmError::UnknownGlobal(n)),
                 });
             },
+            Instr::GStore(n) => {
+                let num1 = self.pop()?;
+                self.globals.insert(n, num1);
+            },
+
+            Instr::Call { target, arguments } => {
+                // Checked before anything moves, so a bad target faults with the stack as it was.
+                if usize::try_from(target).map_or(true, |t| t >= self.instrs.len()) {
+                    return Err(VmError::PcOutOfBounds(target));
+                }
 
-            Opcode::STORE => {
-                let num1 = match self.stack.pop() {
+                let arguments = arguments as usize;
+                let stack_base = match self.stack.len().checked_sub(arguments) {
                     Some(n) => n,
-                    None => return Some("no character to pop".into()),
+                    None => return Err(VmError::ArityMismatch { expected: arguments, found: self.stack.len() }),
                 };
 
-                let mut call = self.call_stack.pop().unwrap();
-                call.store(operand1, num1);
-                self.call_stack.push(call);
+                let frame = Frame::new(self.current_address, stack_base, self.locals.len());
+                self.locals.extend(self.stack.drain(stack_base..));
+                self.call_stack.push(frame);
+                self.current_address = target as usize;
+                return Ok(StepOutcome::Jumped);
             },
 
-            Opcode::CALL => {
-                self.call_stack.push(Frame::new(self.current_address));
-
-                if operand2 == 2 {
-                    self.current_address -= operand1 as usize;
-                }
-                else if operand2 == 1 {
-                    self.current_address += operand1 as usize;
+            Instr::Return(n) => {
+                if self.call_stack.len() <= 1 {
+                    return Err(VmError::CallStackUnderflow);
                 }
-                else {
-                    self.current_address = operand1 as usize;
-                }
-
-                return Some("jumped".into());
-            },
 
-            Opcode::RETURN => {
-                self.current_address = self.call_stack.pop().unwrap().return_address;
+                // The function has to leave exactly its results above where its frame started.
+                let frame = self.call_stack.last().unwrap();
+                let expected = n as usize;
+                match self.stack.len().checked_sub(frame.stack_base) {
+                    Some(found) if found == expected => (),
+                    Some(found) => return Err(VmError::ArityMismatch { expected, found }),
+                    None => return Err(VmError::StackUnderflow),
+                }
+                let frame = self.call_stack.pop().unwrap();
+                self.locals.truncate(frame.locals_base);
+                self.current_address = frame.return_address;
             }
         }
 
-        None
+        Ok(StepOutcome::Continue)
     }
 
     pub fn set_flags(&mut self) {
-        let result = self.stack.pop().unwrap();
+        let result = match self.stack.last() {
+            Some(n) => *n,
+            None => return,
+        };
 
         match result {
             0 => self.zero_flag = true,
             _ => {
                 self.zero_flag = false;
-                match result > 0 {
-                    true => self.sign_flag = true,
-                    false => self.sign_flag = false,
-                }
+                self.sign_flag = result > 0;
             }
         }
+    }
+
+    /// Like `set_flags`, for a float result.
+    pub fn set_float_flags(&mut self, result: f64) {
+        if result == 0.0 {
+            self.zero_flag = true;
+        } else {
+            self.zero_flag = false;
+            self.sign_flag = result > 0.0;
+        }
+    }
+
+    // Applies the overflow mode to the result of an `overflowing_*` operation, given what the
+    // saturating version of it would have produced.
+    fn overflowing(&mut self, (result, overflowed): (Value, bool), saturated: Value) -> Result<Value, VmError> {
+        self.overflow_flag = overflowed;
+        if !overflowed {
+            return Ok(result);
+        }
 
-        self.stack.push(result);
+        match self.overflow_mode {
+            OverflowMode::Wrapping => Ok(result),
+            OverflowMode::Checked => Err(VmError::Overflow),
+            OverflowMode::Saturating => Ok(saturated),
+        }
+    }
+
+    // Where variable `n` of the current frame is, or would be, in `locals`.
+    fn local_index(&self, n: i16) -> Result<usize, VmError> {
+        let base = self.call_stack.last().unwrap().locals_base;
+        match usize::try_from(n) {
+            Ok(offset) => Ok(base + offset),
+            Err(_) => Err(VmError::UnknownVariable(n)),
+        }
+    }
+
+    fn memory_index(&self, address: Value) -> Result<usize, VmError> {
+        match usize::try_from(address) {
+            Ok(index) if index < self.memory.len() => Ok(index),
+            _ => Err(VmError::MemoryOutOfBounds(address)),
+        }
+    }
+
+    // The index of the item `n` places below the top of the stack, where 0 is the top itself.
+    fn nth_from_top(&self, n: usize) -> Result<usize, VmError> {
+        n.checked_add(1)
+            .and_then(|n| self.stack.len().checked_sub(n))
+            .ok_or(VmError::StackUnderflow)
+    }
+
+    fn pop(&mut self) -> Result<Value, VmError> {
+        match self.stack.pop() {
+            Some(n) => Ok(n),
+            None => Err(VmError::StackUnderflow),
+        }
+    }
+
+    // Jumps to an absolute target the predecode pass resolved, which can still be negative.
+    fn jump(&mut self, target: i64) -> Result<StepOutcome, VmError> {
+        if target < 0 {
+            return Err(VmError::PcOutOfBounds(target));
+        }
+        self.current_address = target as usize;
+        Ok(StepOutcome::Jumped)
+    }
+
+    fn check_budget(&self) -> Result<(), VmError> {
+        if let Some(max) = self.limits.max_instructions {
+            if self.executed >= max {
+                return Err(VmError::InstructionLimitExceeded(max));
+            }
+        }
+
+        if let Some(deadline) = self.limits.deadline {
+            if self.executed % DEADLINE_INTERVAL == 0 && Instant::now() >= deadline {
+                return Err(VmError::DeadlineExceeded);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_depth(&self) -> Result<(), VmError> {
+        if let Some(max) = self.limits.max_stack_depth {
+            if self.stack.len() > max {
+                return Err(VmError::StackOverflow(max));
+            }
+        }
+
+        if let Some(max) = self.limits.max_call_depth {
+            // The outermost frame isn't a call.
+            if self.call_stack.len() - 1 > max {
+                return Err(VmError::CallStackOverflow(max));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fault(&self, error: VmError, address: usize) -> Fault {
+        Fault {
+            error,
+            address,
+            instruction: self.program.get(address).map(|i| Opcode::decode(*i)),
+            executed: self.executed,
+        }
     }
 }
@@ -0,0 +1,170 @@
+use crate::vm::cpu::*;
+use crate::vm::error::*;
+use crate::vm::host::*;
+use std::convert::TryFrom;
+
+/// A test against the value on top of the stack.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Condition {
+    Equal(Value),
+    NotEqual(Value),
+    Less(Value),
+    Greater(Value),
+}
+
+impl Condition {
+    pub fn matches(&self, value: Value) -> bool {
+        match *self {
+            Condition::Equal(n) => value == n,
+            Condition::NotEqual(n) => value != n,
+            Condition::Less(n) => value < n,
+            Condition::Greater(n) => value > n,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub address: usize,
+    pub condition: Option<Condition>,
+}
+
+/// Watches a variable in the frame `depth` entries up the call stack, 0 being the outermost frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub depth: usize,
+    pub variable: i16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Step,
+    Halted(Value),
+    Breakpoint(usize),
+    Watchpoint { watchpoint: Watchpoint, old: Option<Value>, new: Option<Value> },
+}
+
+pub struct Debugger<H: Host = StdHost> {
+    pub cpu: CPU<H>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Where the CPU was when the debugger last stopped, so resuming doesn't stop there again.
+    stopped_at: Option<usize>,
+}
+
+impl<H: Host> Debugger<H> {
+    pub fn new(cpu: CPU<H>) -> Debugger<H> {
+        Debugger { cpu, breakpoints: Vec::new(), watchpoints: Vec::new(), stopped_at: None }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.push(Breakpoint { address, condition: None });
+    }
+
+    pub fn add_conditional_breakpoint(&mut self, address: usize, condition: Condition) {
+        self.breakpoints.push(Breakpoint { address, condition: Some(condition) });
+    }
+
+    /// Removes every breakpoint at `address`, returning whether there were any.
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.address != address);
+        len != self.breakpoints.len()
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, depth: usize, variable: i16) {
+        self.watchpoints.push(Watchpoint { depth, variable });
+    }
+
+    pub fn remove_watchpoint(&mut self, depth: usize, variable: i16) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.depth != depth || w.variable != variable);
+        len != self.watchpoints.len()
+    }
+
+    pub fn step(&mut self) -> Result<StopReason, Fault> {
+        let before: Vec<Option<Value>> = self.watchpoints.iter().map(|w| self.watched_value(w)).collect();
+
+        let outcome = self.cpu.step();
+        self.stopped_at = Some(self.cpu.current_address());
+        if outcome? == StepOutcome::Halted {
+            return Ok(StopReason::Halted(self.cpu.stack.last().copied().unwrap_or(0)));
+        }
+
+        for (watchpoint, old) in self.watchpoints.iter().zip(before) {
+            let new = self.watched_value(watchpoint);
+            if new != old {
+                return Ok(StopReason::Watchpoint { watchpoint: *watchpoint, old, new });
+            }
+        }
+
+        Ok(StopReason::Step)
+    }
+
+    /// Keeps stepping until the CPU halts, faults, trips a watchpoint or reaches a breakpoint.
+    /// A breakpoint on the current instruction stops it straight away, unless the debugger has
+    /// already stopped there, so calling this again resumes from a breakpoint.
+    pub fn run_until_breakpoint(&mut self) -> Result<StopReason, Fault> {
+        self.run_while(|_| true)
+    }
+
+    /// Steps over `CALL`s, running the called function until it returns.
+    pub fn step_over(&mut self) -> Result<StopReason, Fault> {
+        let depth = self.cpu.call_stack.len();
+        let reason = self.step()?;
+        if reason != StopReason::Step {
+            return Ok(reason);
+        }
+        self.run_while(|cpu| cpu.call_stack.len() > depth)
+    }
+
+    /// Runs until the current function returns to its caller.
+    pub fn step_out(&mut self) -> Result<StopReason, Fault> {
+        let depth = self.cpu.call_stack.len();
+        self.run_while(|cpu| cpu.call_stack.len() >= depth)
+    }
+
+    fn run_while<F: Fn(&CPU<H>) -> bool>(&mut self, condition: F) -> Result<StopReason, Fault> {
+        if condition(&self.cpu) && self.stopped_at != Some(self.cpu.current_address()) && self.at_breakpoint() {
+            self.stopped_at = Some(self.cpu.current_address());
+            return Ok(StopReason::Breakpoint(self.cpu.current_address()));
+        }
+
+        while condition(&self.cpu) {
+            let reason = self.step()?;
+            if reason != StopReason::Step {
+                return Ok(reason);
+            }
+
+            if self.at_breakpoint() {
+                return Ok(StopReason::Breakpoint(self.cpu.current_address()));
+            }
+        }
+
+        Ok(StopReason::Step)
+    }
+
+    pub fn at_breakpoint(&self) -> bool {
+        let address = self.cpu.current_address();
+        let top = self.cpu.stack.last();
+
+        self.breakpoints.iter().any(|b| b.address == address && match (b.condition, top) {
+            (None, _) => true,
+            (Some(condition), Some(value)) => condition.matches(*value),
+            (Some(_), None) => false,
+        })
+    }
+
+    fn watched_value(&self, watchpoint: &Watchpoint) -> Option<Value> {
+        let index = usize::try_from(watchpoint.variable).ok()?;
+        self.cpu.locals(watchpoint.depth)?.get(index).copied()
+    }
+}
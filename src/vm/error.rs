@@ -0,0 +1,79 @@
+use crate::vm::cpu::Value;
+use crate::vm::instruction::Opcode;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackUnderflow,
+    CallStackUnderflow,
+    UnknownVariable(i16),
+    UnknownGlobal(i16),
+    DivisionByZero,
+    Overflow,
+    IllegalOpcode(u8),
+    PcOutOfBounds(i64),
+    InputParse(String),
+    EndOfInput,
+    InstructionLimitExceeded(u64),
+    DeadlineExceeded,
+    StackOverflow(usize),
+    CallStackOverflow(usize),
+    MemoryOutOfBounds(Value),
+    MemoryLimitExceeded(usize),
+    ArityMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow => write!(f, "no value on the stack to pop"),
+            VmError::CallStackUnderflow => write!(f, "return encountered outside of a function"),
+            VmError::UnknownVariable(v) => write!(f, "{} is not a variable", v),
+            VmError::UnknownGlobal(g) => write!(f, "global {} has not been set", g),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+            VmError::Overflow => write!(f, "arithmetic overflow"),
+            VmError::IllegalOpcode(o) => write!(f, "illegal opcode {:#04x}", o),
+            VmError::PcOutOfBounds(a) => write!(f, "program counter out of bounds: {}", a),
+            VmError::InputParse(s) => write!(f, "couldn't parse input: {}", s),
+            VmError::EndOfInput => write!(f, "no more input available"),
+            VmError::InstructionLimitExceeded(n) => write!(f, "instruction limit of {} exceeded", n),
+            VmError::DeadlineExceeded => write!(f, "deadline exceeded"),
+            VmError::StackOverflow(n) => write!(f, "stack depth limit of {} exceeded", n),
+            VmError::CallStackOverflow(n) => write!(f, "call depth limit of {} exceeded", n),
+            VmError::MemoryOutOfBounds(a) => write!(f, "memory address out of bounds: {}", a),
+            VmError::MemoryLimitExceeded(n) => write!(f, "memory limit of {} words exceeded", n),
+            VmError::ArityMismatch { expected, found } => write!(f, "expected {} value(s) on the stack, found {}", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Whether `CPU::run` should keep going after an instruction has executed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StepOutcome {
+    Continue,
+    Jumped,
+    Halted,
+}
+
+/// A `VmError` along with where in the program it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub error: VmError,
+    pub address: usize,
+    pub instruction: Option<(Opcode, i16, i8)>,
+    /// How many instructions completed before the fault.
+    pub executed: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.instruction {
+            Some(instruction) => write!(f, "{} at instruction {} ({:?})", self.error, self.address, instruction),
+            None => write!(f, "{} at instruction {}", self.error, self.address),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
@@ -1,20 +1,13 @@
-use std::collections::HashMap;
-
 pub struct Frame {
-    variables: HashMap<i16, i16>,
-    pub return_address: usize
+    pub return_address: usize,
+    /// How deep the operand stack was when the frame was entered, once its arguments were taken off.
+    pub stack_base: usize,
+    /// Where the frame's slots start in the CPU's locals. They run up to the next frame's slots.
+    pub locals_base: usize,
 }
 
 impl Frame {
-    pub fn new(return_address: usize) -> Frame {
-        Frame { variables: HashMap::new(), return_address }
-    }
-
-    pub fn store(&mut self, key: i16, value: i16) {
-        self.variables.insert(key, value);
+    pub fn new(return_address: usize, stack_base: usize, locals_base: usize) -> Frame {
+        Frame { return_address, stack_base, locals_base }
     }
-
-    pub fn load(&self, key: &i16) -> Option<&i16> {
-        self.variables.get(key)
-    }
-}
\ No newline at end of file
+}
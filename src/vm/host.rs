@@ -0,0 +1,55 @@
+use std::collections::VecDeque;
+use std::io::{stdin, stdout, Write};
+
+/// Where `STDIN` reads lines from and `STDOUT` writes text to.
+pub trait Host {
+    /// Returns the next line of input without its line ending, or `None` once input is exhausted.
+    fn read_line(&mut self) -> Option<String>;
+    fn write(&mut self, text: &str);
+}
+
+/// Reads from the process's stdin and prints to its stdout.
+#[derive(Default)]
+pub struct StdHost;
+
+impl Host for StdHost {
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(&['\r', '\n'][..]).into()),
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = stdout().flush();
+    }
+}
+
+/// Feeds `STDIN` from a queue of lines and captures everything written by `STDOUT`.
+#[derive(Default)]
+pub struct BufferedHost {
+    pub input: VecDeque<String>,
+    pub output: String,
+}
+
+impl BufferedHost {
+    pub fn new() -> BufferedHost {
+        BufferedHost::default()
+    }
+
+    pub fn with_input<S: Into<String>>(lines: Vec<S>) -> BufferedHost {
+        BufferedHost { input: lines.into_iter().map(|l| l.into()).collect(), output: String::new() }
+    }
+}
+
+impl Host for BufferedHost {
+    fn read_line(&mut self) -> Option<String> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, text: &str) {
+        self.output.push_str(text);
+    }
+}
@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 #[derive(Copy, Clone, PartialEq, std::fmt::Debug)]
 pub enum Opcode {
     HALT,
@@ -32,12 +34,118 @@ pub enum Opcode {
     LOAD,
     STORE,
     CALL,
-    RETURN
+    RETURN,
+
+    PUSHW,
+
+    FADD,
+    FSUB,
+    FMUL,
+    FDIV,
+    FCMP,
+    ITOF,
+    FTOI,
+
+    SWAP,
+    OVER,
+    ROT,
+    NIP,
+    TUCK,
+    PICK,
+    ROLL,
+    DROP,
+
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+
+    MLOAD,
+    MSTORE,
+    MEMSIZE,
+    GROW,
+
+    GLOAD,
+    GSTORE,
+
+    ENTER,
+}
+
+/// What an operand written in `.nar` source means, which also decides where it's encoded.
+#[derive(Copy, Clone, PartialEq, std::fmt::Debug)]
+pub enum OperandKind {
+    /// A value in operand1, written as a number or a single character.
+    Immediate,
+    /// A jump or call address in operand1, written as a number or a label.
+    Target,
+    /// A variable number in operand1.
+    Variable,
+    /// How many items down the stack to reach, in operand1.
+    Count,
+    /// A global variable number in operand1, written as a number or a name declared with `.global`.
+    Global,
+    /// A mode in operand2. It's optional, defaulting to 0.
+    Mode,
+    /// How many values `CALL` moves into the new frame, packed into operand2 above the mode.
+    /// It's optional, defaulting to 0.
+    Arguments,
+    /// How many values `RETURN` hands back to the caller, in operand1. It's optional, defaulting to 0.
+    Results,
+    /// How many local slots `ENTER` reserves, in operand1. Left out, the assembler counts them.
+    Slots,
+    /// A number in operand1 of an opcode that doesn't use it, as old `.nar` files wrote to fill
+    /// out the instruction.
+    Padding,
+}
+
+impl OperandKind {
+    pub fn is_optional(self) -> bool {
+        matches!(self, OperandKind::Mode | OperandKind::Arguments | OperandKind::Results | OperandKind::Slots | OperandKind::Padding)
+    }
 }
 
 impl Opcode {
+    /// The operands an opcode takes in `.nar` source, in the order they're written.
+    pub fn operands(self) -> &'static [OperandKind] {
+        match self {
+            Opcode::PUSH | Opcode::PUSHW => &[OperandKind::Immediate],
+            Opcode::LOAD | Opcode::STORE => &[OperandKind::Variable],
+            Opcode::PICK | Opcode::ROLL | Opcode::DROP => &[OperandKind::Count],
+            Opcode::GLOAD | Opcode::GSTORE => &[OperandKind::Global],
+            // The mode stays where `CALL target mode` always had it, with the argument count after it.
+            Opcode::CALL => &[OperandKind::Target, OperandKind::Mode, OperandKind::Arguments],
+            Opcode::RETURN => &[OperandKind::Results],
+            Opcode::ENTER => &[OperandKind::Slots],
+            Opcode::STDOUT => &[OperandKind::Mode],
+            _ if self.is_branch() => &[OperandKind::Target, OperandKind::Mode],
+            _ => &[],
+        }
+    }
+
+    /// The operands as old `.nar` files wrote them, when every instruction gave both operand1 and
+    /// operand2, e.g. `ADD 0 0` or `PUSH 12 0`. Whatever an opcode doesn't use is still encoded,
+    /// but the CPU ignores it.
+    pub fn padded_operands(self) -> &'static [OperandKind] {
+        match self {
+            Opcode::PUSH | Opcode::PUSHW => &[OperandKind::Immediate, OperandKind::Mode],
+            Opcode::LOAD | Opcode::STORE => &[OperandKind::Variable, OperandKind::Mode],
+            Opcode::PICK | Opcode::ROLL | Opcode::DROP => &[OperandKind::Count, OperandKind::Mode],
+            Opcode::GLOAD | Opcode::GSTORE => &[OperandKind::Global, OperandKind::Mode],
+            Opcode::RETURN => &[OperandKind::Results, OperandKind::Mode],
+            Opcode::ENTER => &[OperandKind::Slots, OperandKind::Mode],
+            // Branches already take both, and CALL's operand2 now holds its argument count too.
+            Opcode::CALL => self.operands(),
+            _ if self.is_branch() => self.operands(),
+            _ => &[OperandKind::Padding, OperandKind::Mode],
+        }
+    }
+
     pub fn encode(opcode: Opcode, operand1: i16, operand2: i8) -> u32 {
-        (u8::from(opcode) as u32) << 24 | (operand1 as u32) << 16 | (operand2 as u32)
+        let [operand1_upper, operand1_lower] = operand1.to_be_bytes();
+        u32::from_be_bytes([u8::from(opcode), operand1_lower, operand1_upper, operand2 as u8])
     }
 
     pub fn decode(instruction: u32) -> (Opcode, i16, i8) {
@@ -49,6 +157,71 @@ impl Opcode {
         )
     }
 
+    /// Whether operand1 is a jump or call target, with operand2 giving its addressing mode.
+    pub fn is_branch(self) -> bool {
+        matches!(self, Opcode::JMP | Opcode::JE | Opcode::JNE | Opcode::JG | Opcode::JL | Opcode::JGE | Opcode::JLE | Opcode::CALL)
+    }
+
+    /// The addressing mode in operand2. `CALL` keeps its argument count in the bits above it.
+    pub fn mode(self, operand2: i8) -> i8 {
+        match self {
+            Opcode::CALL => Opcode::unpack_call(operand2).0,
+            _ => operand2,
+        }
+    }
+
+    /// Whether a branch's operand1 is the target itself, rather than an offset from the branch.
+    /// Mode 1 is forwards and 2 is backwards, and any other mode is absolute, except that `JMP`
+    /// jumps forwards for every mode other than 0 and `JLE` for every mode other than 2.
+    pub fn is_absolute(self, operand2: i8) -> bool {
+        match (self, self.mode(operand2)) {
+            (Opcode::JMP, mode) => mode == 0,
+            (Opcode::JLE, _) => false,
+            (_, mode) => mode != 1 && mode != 2,
+        }
+    }
+
+    /// Resolves a branch's operand1 to an absolute address, which may be negative.
+    pub fn target(self, address: usize, operand1: i16, operand2: i8) -> i64 {
+        if self.is_absolute(operand2) {
+            operand1 as i64
+        } else if self != Opcode::JMP && self.mode(operand2) == 2 {
+            address as i64 - operand1 as i64
+        } else {
+            address as i64 + operand1 as i64
+        }
+    }
+
+    /// Packs `CALL`'s addressing mode and argument count into operand2.
+    pub fn pack_call(mode: i8, arguments: u8) -> i8 {
+        (arguments << 2 | mode as u8 & 0b11) as i8
+    }
+
+    /// Splits `CALL`'s operand2 back into its addressing mode and argument count.
+    pub fn unpack_call(operand2: i8) -> (i8, usize) {
+        (operand2 & 0b11, (operand2 as u8 >> 2) as usize)
+    }
+
+    /// How many words the instruction takes up. `PUSHW` is followed by the high and low 32 bits of its value.
+    pub fn width(self) -> usize {
+        match self {
+            Opcode::PUSHW => 3,
+            _ => 1,
+        }
+    }
+
+    /// Encodes `PUSH value`, switching to `PUSHW` when the value doesn't fit in operand1.
+    pub fn encode_push(value: i64) -> Vec<u32> {
+        match i16::try_from(value) {
+            Ok(n) => vec![Opcode::encode(Opcode::PUSH, n, 0)],
+            Err(_) => Opcode::encode_push_wide(value),
+        }
+    }
+
+    pub fn encode_push_wide(value: i64) -> Vec<u32> {
+        vec![Opcode::encode(Opcode::PUSHW, 0, 0), (value as u64 >> 32) as u32, value as u32]
+    }
+
     pub fn instruction_to_byte_array(instruction: u32) -> [u8; 4] {
         instruction.to_be_bytes()
     }
@@ -91,7 +264,44 @@ impl From<u8> for Opcode {
             22 => Opcode::CALL,
             23 => Opcode::RETURN,
 
-            u8::MAX | _ => Opcode::ILG,
+            24 => Opcode::PUSHW,
+
+            25 => Opcode::FADD,
+            26 => Opcode::FSUB,
+            27 => Opcode::FMUL,
+            28 => Opcode::FDIV,
+            29 => Opcode::FCMP,
+            30 => Opcode::ITOF,
+            31 => Opcode::FTOI,
+
+            32 => Opcode::SWAP,
+            33 => Opcode::OVER,
+            34 => Opcode::ROT,
+            35 => Opcode::NIP,
+            36 => Opcode::TUCK,
+            37 => Opcode::PICK,
+            38 => Opcode::ROLL,
+            39 => Opcode::DROP,
+
+            40 => Opcode::AND,
+            41 => Opcode::OR,
+            42 => Opcode::XOR,
+            43 => Opcode::NOT,
+            44 => Opcode::SHL,
+            45 => Opcode::SHR,
+            46 => Opcode::SAR,
+
+            47 => Opcode::MLOAD,
+            48 => Opcode::MSTORE,
+            49 => Opcode::MEMSIZE,
+            50 => Opcode::GROW,
+
+            51 => Opcode::GLOAD,
+            52 => Opcode::GSTORE,
+
+            53 => Opcode::ENTER,
+
+            _ => Opcode::ILG,
         }
     }
 }
@@ -129,6 +339,43 @@ impl From<Opcode> for u8 {
             Opcode::STORE => 21,
             Opcode::CALL => 22,
             Opcode::RETURN => 23,
+
+            Opcode::PUSHW => 24,
+
+            Opcode::FADD => 25,
+            Opcode::FSUB => 26,
+            Opcode::FMUL => 27,
+            Opcode::FDIV => 28,
+            Opcode::FCMP => 29,
+            Opcode::ITOF => 30,
+            Opcode::FTOI => 31,
+
+            Opcode::SWAP => 32,
+            Opcode::OVER => 33,
+            Opcode::ROT => 34,
+            Opcode::NIP => 35,
+            Opcode::TUCK => 36,
+            Opcode::PICK => 37,
+            Opcode::ROLL => 38,
+            Opcode::DROP => 39,
+
+            Opcode::AND => 40,
+            Opcode::OR => 41,
+            Opcode::XOR => 42,
+            Opcode::NOT => 43,
+            Opcode::SHL => 44,
+            Opcode::SHR => 45,
+            Opcode::SAR => 46,
+
+            Opcode::MLOAD => 47,
+            Opcode::MSTORE => 48,
+            Opcode::MEMSIZE => 49,
+            Opcode::GROW => 50,
+
+            Opcode::GLOAD => 51,
+            Opcode::GSTORE => 52,
+
+            Opcode::ENTER => 53,
         }
     }
 }
@@ -166,7 +413,44 @@ impl From<&String> for Opcode {
             "CALL" => Opcode::CALL,
             "RETURN" => Opcode::RETURN,
 
-            "ILG" | _ => Opcode::ILG,
+            "PUSHW" => Opcode::PUSHW,
+
+            "FADD" => Opcode::FADD,
+            "FSUB" => Opcode::FSUB,
+            "FMUL" => Opcode::FMUL,
+            "FDIV" => Opcode::FDIV,
+            "FCMP" => Opcode::FCMP,
+            "ITOF" => Opcode::ITOF,
+            "FTOI" => Opcode::FTOI,
+
+            "SWAP" => Opcode::SWAP,
+            "OVER" => Opcode::OVER,
+            "ROT" => Opcode::ROT,
+            "NIP" => Opcode::NIP,
+            "TUCK" => Opcode::TUCK,
+            "PICK" => Opcode::PICK,
+            "ROLL" => Opcode::ROLL,
+            "DROP" => Opcode::DROP,
+
+            "AND" => Opcode::AND,
+            "OR" => Opcode::OR,
+            "XOR" => Opcode::XOR,
+            "NOT" => Opcode::NOT,
+            "SHL" => Opcode::SHL,
+            "SHR" => Opcode::SHR,
+            "SAR" => Opcode::SAR,
+
+            "MLOAD" => Opcode::MLOAD,
+            "MSTORE" => Opcode::MSTORE,
+            "MEMSIZE" => Opcode::MEMSIZE,
+            "GROW" => Opcode::GROW,
+
+            "GLOAD" => Opcode::GLOAD,
+            "GSTORE" => Opcode::GSTORE,
+
+            "ENTER" => Opcode::ENTER,
+
+            _ => Opcode::ILG,
         }
     }
 }
\ No newline at end of file
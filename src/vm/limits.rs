@@ -0,0 +1,23 @@
+use std::time::Instant;
+
+/// How many words of heap memory `GROW` may take the CPU up to when `max_memory` is `None`, which is
+/// 1 GiB. Past that, a program could take the host down with it.
+pub const DEFAULT_MAX_MEMORY: usize = 1 << 27;
+
+/// Bounds on how much work a `CPU` may do before it faults. `None` leaves that resource unbounded,
+/// apart from memory, which is bounded by `DEFAULT_MAX_MEMORY`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub max_instructions: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub max_stack_depth: Option<usize>,
+    pub max_call_depth: Option<usize>,
+    /// How many words of heap memory `GROW` may take the CPU up to.
+    pub max_memory: Option<usize>,
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits::default()
+    }
+}
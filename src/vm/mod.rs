@@ -1,3 +1,9 @@
 pub mod cpu;
+pub mod debugger;
+pub mod error;
 pub mod frame;
-pub mod instruction;
\ No newline at end of file
+pub mod host;
+pub mod instruction;
+pub mod limits;
+pub mod predecode;
+pub mod trace;
\ No newline at end of file
@@ -0,0 +1,164 @@
+use crate::vm::cpu::Value;
+use crate::vm::instruction::Opcode;
+
+/// An instruction decoded ahead of time, so the CPU doesn't have to pick apart a `u32` for every
+/// step. Jump and call targets are already absolute, though they may still be negative.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Instr {
+    Halt,
+    Illegal(u8),
+    Len,
+
+    Pop,
+    Push(Value),
+    /// A `PUSHW` with both of its value words.
+    PushWide(Value),
+    /// A `PUSHW` cut off by the end of the program.
+    PushTruncated,
+    Dup,
+
+    Swap,
+    Over,
+    Rot,
+    Nip,
+    Tuck,
+    Pick(i16),
+    Roll(i16),
+    Drop(i16),
+
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Sar,
+
+    MLoad,
+    MStore,
+    MemSize,
+    Grow,
+
+    Cmp,
+
+    FAdd,
+    FSub,
+    FMul,
+    FDiv,
+    FCmp,
+    IToF,
+    FToI,
+
+    Jmp(i64),
+    Je(i64),
+    Jne(i64),
+    Jg(i64),
+    Jl(i64),
+    Jge(i64),
+    Jle(i64),
+
+    Stdin,
+    Stdout(i8),
+
+    Load(i16),
+    Store(i16),
+    Enter(i16),
+    GLoad(i16),
+    GStore(i16),
+
+    Call { target: i64, arguments: u8 },
+    Return(i16),
+}
+
+impl Instr {
+    /// Decodes the instruction at `address`. Every word gets decoded, including the ones holding a
+    /// `PUSHW` value, so a jump into the middle of one behaves just as it would on the raw program.
+    pub fn decode(program: &[u32], address: usize) -> Instr {
+        let instruction = program[address];
+        let (opcode, operand1, operand2) = Opcode::decode(instruction);
+        let target = opcode.target(address, operand1, operand2);
+
+        match opcode {
+            Opcode::HALT => Instr::Halt,
+            Opcode::ILG => Instr::Illegal((instruction >> 24) as u8),
+            Opcode::LEN => Instr::Len,
+
+            Opcode::POP => Instr::Pop,
+            Opcode::PUSH => Instr::Push(operand1 as Value),
+            Opcode::PUSHW => match program.get(address + 1..address + 3) {
+                Some(&[high, low]) => Instr::PushWide(((high as u64) << 32 | low as u64) as Value),
+                _ => Instr::PushTruncated,
+            },
+            Opcode::DUP => Instr::Dup,
+
+            Opcode::SWAP => Instr::Swap,
+            Opcode::OVER => Instr::Over,
+            Opcode::ROT => Instr::Rot,
+            Opcode::NIP => Instr::Nip,
+            Opcode::TUCK => Instr::Tuck,
+            Opcode::PICK => Instr::Pick(operand1),
+            Opcode::ROLL => Instr::Roll(operand1),
+            Opcode::DROP => Instr::Drop(operand1),
+
+            Opcode::ADD => Instr::Add,
+            Opcode::SUB => Instr::Sub,
+            Opcode::MUL => Instr::Mul,
+            Opcode::DIV => Instr::Div,
+            Opcode::MOD => Instr::Mod,
+
+            Opcode::AND => Instr::And,
+            Opcode::OR => Instr::Or,
+            Opcode::XOR => Instr::Xor,
+            Opcode::NOT => Instr::Not,
+            Opcode::SHL => Instr::Shl,
+            Opcode::SHR => Instr::Shr,
+            Opcode::SAR => Instr::Sar,
+
+            Opcode::MLOAD => Instr::MLoad,
+            Opcode::MSTORE => Instr::MStore,
+            Opcode::MEMSIZE => Instr::MemSize,
+            Opcode::GROW => Instr::Grow,
+
+            Opcode::CMP => Instr::Cmp,
+
+            Opcode::FADD => Instr::FAdd,
+            Opcode::FSUB => Instr::FSub,
+            Opcode::FMUL => Instr::FMul,
+            Opcode::FDIV => Instr::FDiv,
+            Opcode::FCMP => Instr::FCmp,
+            Opcode::ITOF => Instr::IToF,
+            Opcode::FTOI => Instr::FToI,
+
+            Opcode::JMP => Instr::Jmp(target),
+            Opcode::JE => Instr::Je(target),
+            Opcode::JNE => Instr::Jne(target),
+            Opcode::JG => Instr::Jg(target),
+            Opcode::JL => Instr::Jl(target),
+            Opcode::JGE => Instr::Jge(target),
+            Opcode::JLE => Instr::Jle(target),
+
+            Opcode::STDIN => Instr::Stdin,
+            Opcode::STDOUT => Instr::Stdout(operand2),
+
+            Opcode::LOAD => Instr::Load(operand1),
+            Opcode::STORE => Instr::Store(operand1),
+            Opcode::ENTER => Instr::Enter(operand1),
+            Opcode::GLOAD => Instr::GLoad(operand1),
+            Opcode::GSTORE => Instr::GStore(operand1),
+
+            Opcode::CALL => Instr::Call { target, arguments: Opcode::unpack_call(operand2).1 as u8 },
+            Opcode::RETURN => Instr::Return(operand1),
+        }
+    }
+}
+
+/// Decodes every word of `program`, so the result can be indexed by the same addresses.
+pub fn predecode(program: &[u32]) -> Vec<Instr> {
+    (0..program.len()).map(|address| Instr::decode(program, address)).collect()
+}
@@ -0,0 +1,146 @@
+use crate::vm::cpu::Value;
+use crate::vm::instruction::Opcode;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Everything that happened while the CPU executed a single instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub address: usize,
+    pub instruction: (Opcode, i16, i8),
+    pub stack_before: Vec<Value>,
+    pub stack_after: Vec<Value>,
+    pub zero_flag: bool,
+    pub sign_flag: bool,
+    pub overflow_flag: bool,
+}
+
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent);
+}
+
+/// Writes one line per instruction, e.g. `   12  STORE 1 0  [3, 3] -> [3]  z=0 s=1 o=0`.
+pub struct TextTrace<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TextTrace<W> {
+    pub fn new(writer: W) -> TextTrace<W> {
+        TextTrace { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> TraceSink for TextTrace<W> {
+    fn record(&mut self, event: &TraceEvent) {
+        let (opcode, operand1, operand2) = event.instruction;
+        let _ = writeln!(self.writer, "{:>5}  {:?} {} {}  {:?} -> {:?}  z={} s={} o={}",
+            event.address, opcode, operand1, operand2,
+            event.stack_before, event.stack_after,
+            event.zero_flag as u8, event.sign_flag as u8, event.overflow_flag as u8);
+    }
+}
+
+/// Writes each event as little-endian binary:
+/// address (u32), opcode (u8), operand1 (i16), operand2 (i8),
+/// flags (u8, zero = bit 0, sign = bit 1, overflow = bit 2),
+/// then the stack before and after, each as a length (u32) followed by that many i64s.
+pub struct BinaryTrace<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> BinaryTrace<W> {
+    pub fn new(writer: W) -> BinaryTrace<W> {
+        BinaryTrace { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> TraceSink for BinaryTrace<W> {
+    fn record(&mut self, event: &TraceEvent) {
+        let (opcode, operand1, operand2) = event.instruction;
+        let mut bytes: Vec<u8> = Vec::new();
+
+        bytes.extend_from_slice(&(event.address as u32).to_le_bytes());
+        bytes.push(u8::from(opcode));
+        bytes.extend_from_slice(&operand1.to_le_bytes());
+        bytes.push(operand2 as u8);
+        bytes.push(event.zero_flag as u8 | (event.sign_flag as u8) << 1 | (event.overflow_flag as u8) << 2);
+
+        for stack in [&event.stack_before, &event.stack_after].iter() {
+            bytes.extend_from_slice(&(stack.len() as u32).to_le_bytes());
+            stack.iter().for_each(|n| bytes.extend_from_slice(&n.to_le_bytes()));
+        }
+
+        let _ = self.writer.write_all(&bytes);
+    }
+}
+
+impl BinaryTrace<Vec<u8>> {
+    /// Reads back events written by a `BinaryTrace`, returning `None` if the data is truncated.
+    pub fn decode(mut bytes: &[u8]) -> Option<Vec<TraceEvent>> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if bytes.len() < n {
+                return None;
+            }
+            let (head, tail) = bytes.split_at(n);
+            *bytes = tail;
+            Some(head)
+        }
+
+        fn take_stack(bytes: &mut &[u8]) -> Option<Vec<Value>> {
+            let len = u32::from_le_bytes(take(bytes, 4)?.try_into().ok()?) as usize;
+            let data = take(bytes, len.checked_mul(8)?)?;
+            Some(data.chunks_exact(8).map(|c| Value::from_le_bytes(c.try_into().unwrap())).collect())
+        }
+
+        let mut events = Vec::new();
+        while !bytes.is_empty() {
+            let address = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?) as usize;
+            let header = take(&mut bytes, 5)?;
+
+            events.push(TraceEvent {
+                address,
+                instruction: (Opcode::from(header[0]), i16::from_le_bytes([header[1], header[2]]), header[3] as i8),
+                zero_flag: header[4] & 1 != 0,
+                sign_flag: header[4] & 2 != 0,
+                overflow_flag: header[4] & 4 != 0,
+                stack_before: take_stack(&mut bytes)?,
+                stack_after: take_stack(&mut bytes)?,
+            });
+        }
+
+        Some(events)
+    }
+}
+
+/// Collects events in memory. Clones share the same events, so keep one to inspect after handing
+/// another to the CPU.
+#[derive(Clone, Default)]
+pub struct VecTrace {
+    events: Rc<RefCell<Vec<TraceEvent>>>,
+}
+
+impl VecTrace {
+    pub fn new() -> VecTrace {
+        VecTrace::default()
+    }
+
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.borrow().clone()
+    }
+}
+
+impl TraceSink for VecTrace {
+    fn record(&mut self, event: &TraceEvent) {
+        self.events.borrow_mut().push(event.clone());
+    }
+}